@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::path::Path;
+
+use image_provider::sanitize_path;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|raw: &str| {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    if let Ok(path) = sanitize_path(root, raw) {
+        // Whatever gets through must stay below the root
+        assert!(path.starts_with(root.canonicalize().unwrap()));
+        assert!(!path.to_string_lossy().contains('\0'));
+    }
+});
@@ -1,71 +1,573 @@
-use clap::Parser;
+use axum::http::{HeaderValue, Method};
+use clap::{ArgAction, Parser, ValueEnum};
 use derive_builder::Builder;
 use fast_image_resize::{FilterType, ResizeAlg};
+use image::{codecs::png::CompressionType, ImageFormat};
 use serde::Deserialize;
+use std::{fmt, path::PathBuf, str::FromStr, time::Duration};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::ImageQuery;
 
 #[derive(Debug, Clone, Deserialize, Parser, Builder)]
-#[builder(pattern = "owned")]
+#[builder(
+    pattern = "owned",
+    build_fn(error = "String", validate = "ResizeConfigBuilder::validate")
+)]
 pub struct ResizeConfig {
+    /// Directory the images are served from
+    #[clap(long, default_value = ".")]
+    #[builder(default = "PathBuf::from(\".\")")]
+    pub root: PathBuf,
+
     /// Filter type to use for resizing
-    /// `lanczos3`  
-    /// `gaussian`  
-    /// `catmull-rom`  
-    /// `hamming`  
-    /// `mitchell`  
-    /// `bilinear`  
-    /// `box`  
-    #[clap(name = "resize-images-filter-type", long, default_value = "lanczos3")]
-    pub filter_type: String,
+    #[clap(
+        name = "resize-images-filter-type",
+        long,
+        value_enum,
+        default_value_t = ResizeFilter::Lanczos3
+    )]
+    #[builder(default = "ResizeFilter::Lanczos3")]
+    pub filter_type: ResizeFilter,
 
     /// Resize algorithm to use
-    /// `super-sampling8x`  
-    /// `super-sampling4x`  
-    /// `super-sampling2x`  
-    /// `convolution`  
-    /// `interpolation`  
-    /// `nearest`  
     /// (nearest will ignore filter_type)
     #[clap(
         name = "resize-images-algorithm",
         long,
-        default_value = "interpolation"
+        value_enum,
+        default_value_t = ResizeAlgorithmKind::Interpolation
     )]
-    pub algorithm: String,
+    #[builder(default = "ResizeAlgorithmKind::Interpolation")]
+    pub algorithm: ResizeAlgorithmKind,
 
     /// Maximum cached images in memory
     #[clap(name = "resize-images-cache-size", long, default_value_t = 200)]
+    #[builder(default = "200")]
     pub cache_size: usize,
+
+    /// Seconds a processed image stays in the cache
+    #[clap(name = "resize-images-cache-lifespan", long, default_value_t = 24 * 60 * 60)]
+    #[builder(default = "24 * 60 * 60")]
+    pub cache_lifespan_secs: u64,
+
+    /// Directory to also keep processed images in, so they survive restarts
+    #[clap(name = "resize-images-cache-dir", long)]
+    #[builder(default)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Also keep a Brotli compressed copy of each disk cache entry, served to clients that
+    /// accept `br`
+    #[clap(
+        name = "resize-images-precompress",
+        long,
+        requires = "resize-images-cache-dir"
+    )]
+    #[builder(default)]
+    pub precompress: bool,
+
+    /// Restart an entry's lifespan whenever it is served from the cache
+    #[clap(
+        name = "resize-images-cache-refresh",
+        long,
+        action = ArgAction::Set,
+        default_value_t = true
+    )]
+    #[builder(default = "true")]
+    pub cache_refresh: bool,
+
+    /// Characters used for `?output=ascii`, from darkest to brightest
+    #[clap(name = "resize-images-ascii-ramp", long, default_value = " .:-=+*#%@")]
+    #[builder(default = "\" .:-=+*#%@\".into()")]
+    pub ascii_ramp: String,
+
+    /// zlib effort for PNG output, `?compress=` overrides it per request
+    #[clap(
+        name = "resize-images-png-compression",
+        long,
+        value_enum,
+        default_value_t = PngCompression::Fast
+    )]
+    #[builder(default = "PngCompression::Fast")]
+    pub png_compression: PngCompression,
+
+    /// Row filter applied before compressing PNG output
+    #[clap(
+        name = "resize-images-png-filter",
+        long,
+        value_enum,
+        default_value_t = PngFilter::Adaptive
+    )]
+    #[builder(default = "PngFilter::Adaptive")]
+    pub png_filter: PngFilter,
+
+    /// AVIF encoding speed `1` (slowest, smallest) to `10`, `?avif_speed=` overrides it per request
+    #[clap(
+        name = "resize-images-avif-speed",
+        long,
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u8).range(1..=10)
+    )]
+    #[builder(default = "4")]
+    pub avif_speed: u8,
+
+    /// JPEG quality `1` to `100` for requests without `?q=`
+    #[clap(
+        name = "resize-images-jpeg-quality",
+        long,
+        default_value_t = crate::DEFAULT_QUALITY,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    #[builder(default = "crate::DEFAULT_QUALITY")]
+    pub jpeg_quality: u8,
+
+    /// AVIF quality `1` to `100` for requests without `?q=`
+    #[clap(
+        name = "resize-images-avif-quality",
+        long,
+        default_value_t = crate::DEFAULT_AVIF_QUALITY,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    #[builder(default = "crate::DEFAULT_AVIF_QUALITY")]
+    pub avif_quality: u8,
+
+    /// Encode WebP lossy at this quality (`0` to `100`) for requests without `?q=`, instead of
+    /// lossless. `?lossless=1` still gets lossless output
+    #[clap(
+        name = "resize-images-webp-quality",
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    #[builder(default)]
+    pub webp_quality: Option<u8>,
+
+    /// Origins allowed by CORS (comma separated), `*` allows any origin.
+    /// Without it no CORS layer is applied, leaving CORS to the embedding application
+    #[clap(
+        name = "cors-origins",
+        long,
+        value_delimiter = ',',
+        value_parser = parse_cors_origin
+    )]
+    #[builder(default)]
+    pub cors_origins: Option<Vec<String>>,
+
+    /// Methods allowed by CORS (comma separated)
+    #[clap(
+        name = "resize-images-cors-allow-methods",
+        long,
+        value_delimiter = ',',
+        default_value = "GET,HEAD",
+        value_parser = parse_cors_method
+    )]
+    #[builder(default = "vec![\"GET\".into(), \"HEAD\".into()]")]
+    pub cors_allow_methods: Vec<String>,
+
+    /// Seconds a CORS preflight response may be cached
+    #[clap(name = "resize-images-cors-max-age", long)]
+    #[builder(default)]
+    pub cors_max_age: Option<u64>,
+
+    /// Seconds clients and CDNs may cache `/info` responses, revalidated by ETag afterwards
+    #[clap(name = "resize-images-info-max-age", long, default_value_t = 300)]
+    #[builder(default = "300")]
+    pub info_max_age: u64,
+
+    /// Allow clients to set `max_age` and `s_maxage` in the query
+    #[clap(name = "resize-images-allow-cache-control-override", long)]
+    #[builder(default)]
+    pub allow_cache_control_override: bool,
+
+    /// Allow `?output=raw` to return uncompressed pixel data
+    #[clap(name = "resize-images-allow-raw-output", long)]
+    #[builder(default)]
+    pub allow_raw_output: bool,
+
+    /// Allow `?grid=<cols>x<rows>` to split images into tiles
+    #[clap(name = "resize-images-allow-grid-split", long)]
+    #[builder(default)]
+    pub allow_grid_split: bool,
+
+    /// Allow `GET /?dir=` to list the images in a directory
+    #[clap(name = "resize-images-allow-directory-index", long)]
+    #[builder(default)]
+    pub allow_directory_index: bool,
+
+    /// Maximum number of images `?stitch=` may concatenate
+    #[clap(name = "resize-images-max-stitch-images", long, default_value_t = 8)]
+    #[builder(default = "8")]
+    pub max_stitch_images: usize,
+
+    /// Size of the `?watermark=` image, as a fraction of the output's shorter side
+    #[clap(name = "resize-images-watermark-scale", long, default_value_t = 0.15)]
+    #[builder(default = "0.15")]
+    pub watermark_scale: f32,
+
+    /// Opacity of the `?watermark=` image, `0` (invisible) to `1`
+    #[clap(name = "resize-images-watermark-opacity", long, default_value_t = 0.5)]
+    #[builder(default = "0.5")]
+    pub watermark_opacity: f32,
+
+    /// Allow `?psnr=1`, which decodes every output again to measure it
+    #[clap(name = "resize-images-allow-psnr", long)]
+    #[builder(default)]
+    pub allow_psnr: bool,
+
+    /// Allow `?ssim=1`, which decodes every output again to measure it
+    #[clap(name = "resize-images-allow-ssim", long)]
+    #[builder(default)]
+    pub allow_ssim: bool,
+
+    /// Downsample with bilinear to 2x the target before the final resize, much faster for thumbnails
+    #[clap(name = "resize-images-fast-thumbnail", long)]
+    #[builder(default)]
+    pub fast_thumbnail: bool,
+
+    /// Resize JPEG to JPEG without re-encoding. Not supported yet: JPEG is still re-encoded
+    /// (with generation loss), and a warning is logged whenever that happens
+    #[clap(name = "resize-images-lossless-jpeg-pass-through", long)]
+    #[builder(default)]
+    pub lossless_jpeg_pass_through: bool,
+
+    /// Serve the original file when a resize or format change encodes larger than it
+    #[clap(name = "resize-images-avoid-enlargement", long)]
+    #[builder(default)]
+    pub avoid_enlargement: bool,
+
+    /// Image served with 404 for missing paths, processed with the request's parameters
+    #[clap(name = "resize-images-not-found-image", long)]
+    #[builder(default)]
+    pub not_found_image: Option<PathBuf>,
+
+    /// Keep separate cache entries for each client IP, needs the router served with connect info
+    #[clap(name = "resize-images-per-ip-cache", long)]
+    #[builder(default)]
+    pub per_ip_cache: bool,
+
+    /// Maximum number of intermediate sizes in `?chained_resize=`
+    #[clap(name = "resize-images-max-chained-steps", long, default_value_t = 4)]
+    #[builder(default = "4")]
+    pub max_chained_steps: usize,
+
+    /// Parameters applied by `?thumb=1`, defaults to a 300x300 WebP
+    #[clap(skip)]
+    #[builder(default)]
+    pub thumbnail_preset: Option<ImageQuery>,
+
+    /// Maximum number of frames in `?output=thumbnail_strip`
+    #[clap(name = "resize-images-max-strip-count", long, default_value_t = 10)]
+    #[builder(default = "10")]
+    pub max_strip_count: usize,
+
+    /// Width and height of the tiles returned by `?tile_zoom=&tile_x=&tile_y=`
+    #[clap(name = "resize-images-tile-size", long, default_value_t = 256)]
+    #[builder(default = "256")]
+    pub tile_size: u32,
+
+    /// Largest output width in pixels (after `dpr`), wider requests are rejected
+    #[clap(name = "resize-images-max-width", long, default_value_t = 4096)]
+    #[builder(default = "4096")]
+    pub max_width: u32,
+
+    /// Largest output height in pixels (after `dpr`), taller requests are rejected
+    #[clap(name = "resize-images-max-height", long, default_value_t = 4096)]
+    #[builder(default = "4096")]
+    pub max_height: u32,
+
+    /// Highest `dpr`, larger values are lowered to it
+    #[clap(name = "resize-images-max-dpr", long, default_value_t = 3)]
+    #[builder(default = "3")]
+    pub max_dpr: u32,
+
+    /// Require image URLs to carry `s`, an HMAC-SHA256 of the request made with this secret
+    #[clap(name = "resize-images-secret", long, env = "RESIZE_IMAGES_SECRET")]
+    #[builder(default)]
+    pub secret: Option<String>,
+
+    /// Largest encoded output in bytes, larger results are rejected with 413.
+    /// `?output_size_limit=` can only lower it
+    #[clap(name = "resize-images-output-size-limit", long)]
+    #[builder(default)]
+    pub output_size_limit: Option<u64>,
+
+    /// Largest source file in bytes, larger files are rejected with 413 before being read
+    #[clap(name = "resize-images-max-source-file-bytes", long)]
+    #[builder(default)]
+    pub max_source_file_bytes: Option<u64>,
+
+    /// Most bytes a single request may ask for with `Range`, larger ranges are rejected with 416
+    #[clap(name = "resize-images-max-range-size", long)]
+    #[builder(default)]
+    pub max_range_size: Option<u64>,
+
+    /// Serve `/__cache/stats`, `POST /__cache/clear` and `/metrics`, which expose cache internals
+    #[clap(name = "resize-images-enable-admin-endpoints", long)]
+    #[builder(default)]
+    pub enable_admin_endpoints: bool,
+
+    /// Serve `POST /upload`, which converts a `multipart/form-data` image to `upload_output`
+    #[clap(name = "resize-images-enable-upload", long)]
+    #[builder(default)]
+    pub enable_upload: bool,
+
+    /// Formats `POST /upload` accepts by extension (comma separated), detected from the content.
+    /// Every decodable format when empty
+    #[clap(
+        name = "resize-images-upload-allowed-extensions",
+        long,
+        value_delimiter = ','
+    )]
+    #[builder(default)]
+    pub upload_allowed_extensions: Vec<String>,
+
+    /// Format `POST /upload` converts to (e.g. `webp`), the uploaded format when unset
+    #[clap(
+        name = "resize-images-upload-output",
+        long,
+        value_parser = parse_upload_output
+    )]
+    #[builder(default)]
+    pub upload_output: Option<String>,
+
+    /// Liveness probe path, empty to disable
+    #[clap(name = "resize-images-health-path", long, default_value = "/healthz")]
+    #[builder(default = "\"/healthz\".to_string()")]
+    pub health_path: String,
+
+    /// Readiness probe path (checks the root is readable), empty to disable
+    #[clap(name = "resize-images-readiness-path", long, default_value = "/_ready")]
+    #[builder(default = "\"/_ready\".to_string()")]
+    pub readiness_path: String,
+
+    /// Service that `?enhance=` posts the source image to, disabled when unset
+    #[clap(name = "resize-images-enhance-service-url", long)]
+    #[builder(default)]
+    pub enhance_service_url: Option<String>,
+
+    /// Milliseconds to wait for the enhancement service
+    #[clap(name = "resize-images-enhance-timeout", long, default_value_t = 10_000)]
+    #[builder(default = "10_000")]
+    pub enhance_timeout_ms: u64,
+
+    /// Consecutive enhancement failures before the service is skipped for a cooldown
+    #[clap(
+        name = "resize-images-enhance-failure-threshold",
+        long,
+        default_value_t = 5
+    )]
+    #[builder(default = "5")]
+    pub enhance_failure_threshold: u32,
+
+    /// Seconds to reject `?enhance=` after the failure threshold is reached
+    #[clap(name = "resize-images-enhance-cooldown", long, default_value_t = 30)]
+    #[builder(default = "30")]
+    pub enhance_cooldown_secs: u64,
 }
 
 impl ResizeConfig {
-    pub fn builder() -> ResizeConfigBuilder {
-        ResizeConfigBuilder {
-            filter_type: Some("lanczos3".into()),
-            algorithm: Some("interpolation".into()),
-            cache_size: Some(200),
-        }
+    pub fn thumbnail_preset(&self) -> ImageQuery {
+        self.thumbnail_preset.clone().unwrap_or_else(|| ImageQuery {
+            output: Some("webp".to_string()),
+            w: Some(300),
+            h: Some(300),
+            fit: Some("cover".to_string()),
+            q: Some(75),
+            ..Default::default()
+        })
     }
 
     pub fn resize_algorithm(&self) -> ResizeAlg {
-        let filter_type = match self.filter_type.as_str() {
-            "lanczos3" => FilterType::Lanczos3,
-            "gaussian" => FilterType::Gaussian,
-            "catmull-rom" => FilterType::CatmullRom,
-            "hamming" => FilterType::Hamming,
-            "mitchell" => FilterType::Mitchell,
-            "bilinear" => FilterType::Bilinear,
-            "box" => FilterType::Box,
-            _ => panic!("Unsupported filter type"),
+        let filter_type = match self.filter_type {
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Hamming => FilterType::Hamming,
+            ResizeFilter::Mitchell => FilterType::Mitchell,
+            ResizeFilter::Bilinear => FilterType::Bilinear,
+            ResizeFilter::Box => FilterType::Box,
         };
 
-        match self.algorithm.as_str() {
-            "super-sampling8x" => ResizeAlg::SuperSampling(filter_type, 8),
-            "super-sampling4x" => ResizeAlg::SuperSampling(filter_type, 4),
-            "super-sampling2x" => ResizeAlg::SuperSampling(filter_type, 2),
-            "interpolation" => ResizeAlg::Interpolation(filter_type),
-            "convolution" => ResizeAlg::Convolution(filter_type),
-            "nearest" => ResizeAlg::Nearest,
-            _ => panic!("Unsupported resize algorithm"),
+        match self.algorithm {
+            ResizeAlgorithmKind::SuperSampling8x => ResizeAlg::SuperSampling(filter_type, 8),
+            ResizeAlgorithmKind::SuperSampling4x => ResizeAlg::SuperSampling(filter_type, 4),
+            ResizeAlgorithmKind::SuperSampling2x => ResizeAlg::SuperSampling(filter_type, 2),
+            ResizeAlgorithmKind::Interpolation => ResizeAlg::Interpolation(filter_type),
+            ResizeAlgorithmKind::Convolution => ResizeAlg::Convolution(filter_type),
+            ResizeAlgorithmKind::Nearest => ResizeAlg::Nearest,
         }
     }
+
+    /// CORS for `cors_origins`, `None` leaves CORS to the embedding application
+    pub fn cors_layer(&self) -> Option<CorsLayer> {
+        // `--cors-origins ""` parses as a single empty origin
+        let allowed: Vec<&String> = self
+            .cors_origins
+            .as_ref()?
+            .iter()
+            .filter(|origin| !origin.trim().is_empty())
+            .collect();
+        if allowed.is_empty() {
+            return None;
+        }
+
+        // Both are checked when the config is parsed or built
+        let origins = if allowed.iter().any(|origin| origin.as_str() == "*") {
+            AllowOrigin::from(Any)
+        } else {
+            AllowOrigin::list(
+                allowed
+                    .iter()
+                    .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+            )
+        };
+
+        let methods = self
+            .cors_allow_methods
+            .iter()
+            .filter_map(|method| Method::from_bytes(method.as_bytes()).ok())
+            .collect::<Vec<_>>();
+
+        let mut cors = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods);
+        if let Some(max_age) = self.cors_max_age {
+            cors = cors.max_age(Duration::from_secs(max_age));
+        }
+        Some(cors)
+    }
+}
+
+/// Filter used by the convolution based resize algorithms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeFilter {
+    Lanczos3,
+    Gaussian,
+    CatmullRom,
+    Hamming,
+    Mitchell,
+    Bilinear,
+    Box,
 }
+
+/// Resize algorithm, `super-sampling*` resize in several steps of the given factor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizeAlgorithmKind {
+    SuperSampling8x,
+    SuperSampling4x,
+    SuperSampling2x,
+    Convolution,
+    Interpolation,
+    Nearest,
+}
+
+/// zlib effort for PNG output, `fast` is quickest and `best` is smallest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PngCompression {
+    Default,
+    Fast,
+    Best,
+}
+
+/// PNG row filter, `adaptive` picks the best one for every row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    Adaptive,
+}
+
+impl ResizeConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(Some(origins)) = &self.cors_origins {
+            for origin in origins {
+                parse_cors_origin(origin)?;
+            }
+        }
+        if let Some(methods) = &self.cors_allow_methods {
+            for method in methods {
+                parse_cors_method(method)?;
+            }
+        }
+        if let Some(Some(output)) = &self.upload_output {
+            parse_upload_output(output)?;
+        }
+        if self.precompress == Some(true) && !matches!(self.cache_dir, Some(Some(_))) {
+            return Err("precompress requires cache_dir".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// An origin usable in `Access-Control-Allow-Origin`, blank ones are skipped by `cors_layer`
+fn parse_cors_origin(origin: &str) -> Result<String, String> {
+    HeaderValue::from_str(origin)
+        .map(|_| origin.to_string())
+        .map_err(|_| format!("Unsupported CORS origin: {origin:?}"))
+}
+
+/// One of the formats [`encode_image`](crate::encode_image) supports
+fn parse_upload_output(output: &str) -> Result<String, String> {
+    use ImageFormat::*;
+    ImageFormat::from_extension(output)
+        .filter(|format| matches!(format, WebP | Png | Jpeg | Avif | Tiff | Gif))
+        .map(|_| output.to_string())
+        .ok_or_else(|| format!("Unsupported upload output: {output:?}"))
+}
+
+fn parse_cors_method(method: &str) -> Result<String, String> {
+    Method::from_bytes(method.as_bytes())
+        .map(|_| method.to_string())
+        .map_err(|_| format!("Unsupported CORS method: {method:?}"))
+}
+
+impl From<PngCompression> for CompressionType {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+impl From<PngFilter> for image::codecs::png::FilterType {
+    fn from(filter: PngFilter) -> Self {
+        use image::codecs::png::FilterType;
+        match filter {
+            PngFilter::None => FilterType::NoFilter,
+            PngFilter::Sub => FilterType::Sub,
+            PngFilter::Up => FilterType::Up,
+            PngFilter::Avg => FilterType::Avg,
+            PngFilter::Paeth => FilterType::Paeth,
+            PngFilter::Adaptive => FilterType::Adaptive,
+        }
+    }
+}
+
+/// `Display` and `FromStr` using the same names as the CLI
+macro_rules! value_enum_str {
+    ($($name: ident),+) => {$(
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.to_possible_value().unwrap().get_name())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                <Self as ValueEnum>::from_str(s, false)
+            }
+        }
+    )+};
+}
+
+value_enum_str!(ResizeFilter, ResizeAlgorithmKind, PngCompression, PngFilter);
@@ -0,0 +1,579 @@
+use std::io::Write;
+
+use image::{
+    error::{EncodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind},
+    ExtendedColorType, ImageEncoder, ImageError, ImageFormat, ImageResult,
+};
+use webp::{Encoder, PixelLayout, WebPConfig};
+
+/// WebP encoder backed by libwebp, exposing the knobs `image`'s encoder does not.
+pub struct WebPEncoder<W: Write> {
+    writer: W,
+    quality: Option<f32>,
+    alpha_quality: Option<u8>,
+    method: u8,
+    segments: u8,
+    exact: bool,
+}
+
+impl<W: Write> WebPEncoder<W> {
+    pub fn new_lossless(writer: W) -> Self {
+        WebPEncoder {
+            writer,
+            quality: None,
+            alpha_quality: None,
+            method: 4,
+            segments: 4,
+            exact: false,
+        }
+    }
+
+    /// Lossy encoding, `quality` from `0` to `100`
+    pub fn new_lossy(writer: W, quality: f32) -> Self {
+        WebPEncoder {
+            quality: Some(quality),
+            ..Self::new_lossless(writer)
+        }
+    }
+
+    /// Alpha channel quality from `0` to `100` (lossless at `100`), only used when lossy
+    pub fn with_alpha_quality(mut self, alpha_quality: Option<u8>) -> Self {
+        self.alpha_quality = alpha_quality;
+        self
+    }
+
+    /// Compression effort from `0` (fastest) to `6` (slowest, smallest)
+    pub fn with_method(mut self, method: u8) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Number of segments (`1` to `4`) that get their own quantization, only used when lossy
+    pub fn with_segments(mut self, segments: u8) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Preserve the exact RGB values of transparent pixels
+    pub fn with_exact(mut self, exact: bool) -> Self {
+        self.exact = exact;
+        self
+    }
+
+    fn config(&self) -> ImageResult<WebPConfig> {
+        let mut config = WebPConfig::new().map_err(|_| encoding_error("Invalid WebP config"))?;
+        match self.quality {
+            Some(quality) => {
+                config.lossless = 0;
+                config.quality = quality;
+                if let Some(alpha_quality) = self.alpha_quality {
+                    config.alpha_quality = alpha_quality.into();
+                }
+            }
+            None => config.lossless = 1,
+        }
+        config.method = self.method.into();
+        config.segments = self.segments.into();
+        config.exact = self.exact.into();
+        Ok(config)
+    }
+}
+
+impl<W: Write> ImageEncoder for WebPEncoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        // libwebp only accepts RGB(A), so expand grayscale input
+        let (pixels, layout) = match color_type {
+            ExtendedColorType::Rgb8 => (buf.to_vec(), PixelLayout::Rgb),
+            ExtendedColorType::Rgba8 => (buf.to_vec(), PixelLayout::Rgba),
+            ExtendedColorType::L8 => (
+                buf.iter().flat_map(|&l| [l, l, l]).collect(),
+                PixelLayout::Rgb,
+            ),
+            ExtendedColorType::La8 => (
+                buf.chunks_exact(2)
+                    .flat_map(|la| [la[0], la[0], la[0], la[1]])
+                    .collect(),
+                PixelLayout::Rgba,
+            ),
+            _ => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(
+                        ImageFormat::WebP.into(),
+                        UnsupportedErrorKind::Color(color_type),
+                    ),
+                ))
+            }
+        };
+
+        let config = self.config()?;
+        let memory = Encoder::new(&pixels, layout, width, height)
+            .encode_advanced(&config)
+            .map_err(|e| encoding_error(format!("{e:?}")))?;
+
+        self.writer.write_all(&memory)?;
+        Ok(())
+    }
+}
+
+fn encoding_error(message: impl Into<String>) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::WebP),
+        message.into(),
+    ))
+}
+
+/// JPEG 2000 (`.jp2`) encoder backed by OpenJPEG.
+#[cfg(feature = "jpeg2000")]
+pub struct Jpeg2000Encoder<W: Write> {
+    writer: W,
+    ratio: Option<f32>,
+}
+
+#[cfg(feature = "jpeg2000")]
+impl<W: Write> Jpeg2000Encoder<W> {
+    pub fn new_lossless(writer: W) -> Self {
+        Jpeg2000Encoder {
+            writer,
+            ratio: None,
+        }
+    }
+
+    /// Lossy compression ratio, e.g. `20.0` for 20:1
+    pub fn with_ratio(mut self, ratio: Option<f32>) -> Self {
+        self.ratio = ratio;
+        self
+    }
+}
+
+#[cfg(feature = "jpeg2000")]
+impl<W: Write> ImageEncoder for Jpeg2000Encoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        use openjpeg_sys as opj;
+        use std::{ffi::c_void, io::Cursor, slice};
+
+        let channels: u32 = match color_type {
+            ExtendedColorType::L8 => 1,
+            ExtendedColorType::La8 => 2,
+            ExtendedColorType::Rgb8 => 3,
+            ExtendedColorType::Rgba8 => 4,
+            _ => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(
+                        ImageFormatHint::Name("jpeg2000".into()),
+                        UnsupportedErrorKind::Color(color_type),
+                    ),
+                ))
+            }
+        };
+        let has_alpha = matches!(
+            color_type,
+            ExtendedColorType::La8 | ExtendedColorType::Rgba8
+        );
+
+        unsafe extern "C" fn write(buffer: *mut c_void, len: usize, data: *mut c_void) -> usize {
+            let output = &mut *(data as *mut Cursor<Vec<u8>>);
+            let buffer = slice::from_raw_parts(buffer as *const u8, len);
+            output.write_all(buffer).map(|_| len).unwrap_or(usize::MAX)
+        }
+        unsafe extern "C" fn skip(len: i64, data: *mut c_void) -> i64 {
+            let output = &mut *(data as *mut Cursor<Vec<u8>>);
+            output.set_position((output.position() as i64 + len) as u64);
+            len
+        }
+        unsafe extern "C" fn seek(position: i64, data: *mut c_void) -> i32 {
+            let output = &mut *(data as *mut Cursor<Vec<u8>>);
+            output.set_position(position as u64);
+            1
+        }
+
+        let mut output = Cursor::new(Vec::new());
+        let encoded = unsafe {
+            let mut params: opj::opj_cparameters_t = std::mem::zeroed();
+            opj::opj_set_default_encoder_parameters(&mut params);
+            if let Some(ratio) = self.ratio {
+                params.tcp_numlayers = 1;
+                params.tcp_rates[0] = ratio;
+                params.cp_disto_alloc = 1;
+                params.irreversible = 1;
+            }
+            params.tcp_mct = (channels >= 3) as _;
+            // Every resolution level halves the image, small images need fewer levels
+            let levels = u32::BITS - width.min(height).max(1).leading_zeros();
+            params.numresolution = params.numresolution.min(levels as _);
+
+            let mut components: Vec<_> = (0..channels)
+                .map(|_| opj::opj_image_cmptparm_t {
+                    dx: 1,
+                    dy: 1,
+                    w: width,
+                    h: height,
+                    x0: 0,
+                    y0: 0,
+                    prec: 8,
+                    bpp: 8,
+                    sgnd: 0,
+                })
+                .collect();
+            let color_space = if channels >= 3 {
+                opj::COLOR_SPACE::OPJ_CLRSPC_SRGB
+            } else {
+                opj::COLOR_SPACE::OPJ_CLRSPC_GRAY
+            };
+            let image = opj::opj_image_create(channels, components.as_mut_ptr(), color_space);
+            if image.is_null() {
+                return Err(jpeg2000_error("Failed to allocate image"));
+            }
+            (*image).x1 = width;
+            (*image).y1 = height;
+
+            let pixels = (width * height) as usize;
+            let components = slice::from_raw_parts_mut((*image).comps, channels as usize);
+            for (channel, component) in components.iter_mut().enumerate() {
+                component.alpha = (has_alpha && channel == channels as usize - 1) as u16;
+                let data = slice::from_raw_parts_mut(component.data, pixels);
+                for (value, pixel) in data.iter_mut().zip(buf.chunks_exact(channels as usize)) {
+                    *value = pixel[channel].into();
+                }
+            }
+
+            let codec = opj::opj_create_compress(opj::CODEC_FORMAT::OPJ_CODEC_JP2);
+            let stream = opj::opj_stream_default_create(0);
+            opj::opj_stream_set_user_data(stream, &mut output as *mut _ as *mut c_void, None);
+            opj::opj_stream_set_write_function(stream, Some(write));
+            opj::opj_stream_set_skip_function(stream, Some(skip));
+            opj::opj_stream_set_seek_function(stream, Some(seek));
+
+            let encoded = opj::opj_setup_encoder(codec, &mut params, image) != 0
+                && opj::opj_start_compress(codec, image, stream) != 0
+                && opj::opj_encode(codec, stream) != 0
+                && opj::opj_end_compress(codec, stream) != 0;
+
+            opj::opj_stream_destroy(stream);
+            opj::opj_destroy_codec(codec);
+            opj::opj_image_destroy(image);
+            encoded
+        };
+
+        if !encoded {
+            return Err(jpeg2000_error("Failed to encode image"));
+        }
+        self.writer.write_all(output.get_ref())?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "jpeg2000")]
+fn jpeg2000_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Name("jpeg2000".into()),
+        message.to_string(),
+    ))
+}
+
+/// Palette PNG encoder for RGBA images with at most 256 distinct colors.
+#[cfg(feature = "pngquant")]
+pub struct IndexedPngEncoder<W: Write> {
+    writer: W,
+}
+
+#[cfg(feature = "pngquant")]
+impl<W: Write> IndexedPngEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        IndexedPngEncoder { writer }
+    }
+}
+
+#[cfg(feature = "pngquant")]
+impl<W: Write> ImageEncoder for IndexedPngEncoder<W> {
+    fn write_image(
+        self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        use std::collections::HashMap;
+
+        if color_type != ExtendedColorType::Rgba8 {
+            return Err(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    ImageFormat::Png.into(),
+                    UnsupportedErrorKind::Color(color_type),
+                ),
+            ));
+        }
+
+        let mut palette: HashMap<[u8; 4], u8> = HashMap::new();
+        let mut colors = vec![];
+        let mut indices = Vec::with_capacity(buf.len() / 4);
+        for pixel in buf.chunks_exact(4) {
+            let color = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            let index = match palette.get(&color) {
+                Some(&index) => index,
+                None => {
+                    let index = u8::try_from(colors.len())
+                        .map_err(|_| png_error("More than 256 colors"))?;
+                    palette.insert(color, index);
+                    colors.push(color);
+                    index
+                }
+            };
+            indices.push(index);
+        }
+
+        let mut encoder = png::Encoder::new(self.writer, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(
+            colors
+                .iter()
+                .flat_map(|c| [c[0], c[1], c[2]])
+                .collect::<Vec<_>>(),
+        );
+        encoder.set_trns(colors.iter().map(|c| c[3]).collect::<Vec<_>>());
+
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| png_error(&e.to_string()))?;
+        writer
+            .write_image_data(&indices)
+            .map_err(|e| png_error(&e.to_string()))?;
+        writer.finish().map_err(|e| png_error(&e.to_string()))
+    }
+}
+
+#[cfg(feature = "pngquant")]
+fn png_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        message.to_string(),
+    ))
+}
+
+/// JPEG encoder backed by mozjpeg, with optimized Huffman tables.
+#[cfg(feature = "mozjpeg")]
+pub struct MozJpegEncoder<W: Write> {
+    writer: W,
+    quality: u8,
+}
+
+#[cfg(feature = "mozjpeg")]
+impl<W: Write> MozJpegEncoder<W> {
+    /// `quality` from `0` to `100`
+    pub fn new_with_quality(writer: W, quality: u8) -> Self {
+        MozJpegEncoder { writer, quality }
+    }
+}
+
+#[cfg(feature = "mozjpeg")]
+impl<W: Write> ImageEncoder for MozJpegEncoder<W> {
+    fn write_image(
+        self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        use mozjpeg::{ColorSpace, Compress};
+
+        let color_space = match color_type {
+            ExtendedColorType::L8 => ColorSpace::JCS_GRAYSCALE,
+            ExtendedColorType::Rgb8 => ColorSpace::JCS_RGB,
+            _ => {
+                return Err(ImageError::Unsupported(
+                    UnsupportedError::from_format_and_kind(
+                        ImageFormat::Jpeg.into(),
+                        UnsupportedErrorKind::Color(color_type),
+                    ),
+                ))
+            }
+        };
+
+        let mut compress = Compress::new(color_space);
+        compress.set_size(width as usize, height as usize);
+        compress.set_quality(self.quality.into());
+        compress.set_optimize_coding(true);
+
+        let mut compress = compress.start_compress(self.writer)?;
+        compress.write_scanlines(buf)?;
+        compress.finish()?;
+        Ok(())
+    }
+}
+
+/// AVIF encoder driving rav1e directly, for an explicit tile layout. `image`'s encoder only
+/// derives the tiles from its thread count.
+pub struct TiledAvifEncoder<W: Write> {
+    writer: W,
+    speed: u8,
+    quality: u8,
+    tiles: (usize, usize),
+}
+
+impl<W: Write> TiledAvifEncoder<W> {
+    /// `speed` from `1` (slowest) to `10`, `quality` from `1` to `100`
+    pub fn new_with_speed_quality(writer: W, speed: u8, quality: u8) -> Self {
+        TiledAvifEncoder {
+            writer,
+            speed,
+            quality,
+            tiles: (1, 1),
+        }
+    }
+
+    /// Tile columns and rows, each rounded up to a power of two. rav1e then clamps them to what
+    /// the image size allows
+    pub fn with_tiles(mut self, cols: usize, rows: usize) -> Self {
+        self.tiles = (cols.next_power_of_two(), rows.next_power_of_two());
+        self
+    }
+
+    fn config(&self, width: u32, height: u32, monochrome: bool) -> rav1e::Config {
+        use rav1e::prelude::*;
+
+        let (tile_cols, tile_rows) = self.tiles;
+        let color_description = ColorDescription {
+            color_primaries: ColorPrimaries::BT709,
+            transfer_characteristics: TransferCharacteristics::SRGB,
+            matrix_coefficients: MatrixCoefficients::BT601,
+        };
+        let quantizer = avif_quantizer(self.quality);
+        // A single frame needs no lookahead or scene detection
+        let mut speed_settings = SpeedSettings::from_preset(self.speed);
+        speed_settings.rdo_lookahead_frames = 1;
+        speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
+        let encoder = EncoderConfig {
+            width: width as usize,
+            height: height as usize,
+            bit_depth: 8,
+            chroma_sampling: match monochrome {
+                true => ChromaSampling::Cs400,
+                false => ChromaSampling::Cs444,
+            },
+            pixel_range: PixelRange::Full,
+            color_description: (!monochrome).then_some(color_description),
+            still_picture: true,
+            // Frame rate limits the tile size too, one frame per second leaves that to the size
+            time_base: Rational::new(1, 1),
+            quantizer,
+            min_quantizer: quantizer as u8,
+            tile_cols,
+            tile_rows,
+            speed_settings,
+            ..Default::default()
+        };
+        // Without `with_threads`, rav1e runs on the global rayon pool instead of building its own
+        Config::new().with_encoder_config(encoder)
+    }
+
+    /// Rows of `planes` samples per pixel encoded as a single AV1 key frame
+    fn encode_av1(
+        &self,
+        width: u32,
+        height: u32,
+        planes: &[Vec<u8>],
+    ) -> Result<Vec<u8>, rav1e::EncoderStatus> {
+        use rav1e::prelude::*;
+
+        let config = self.config(width, height, planes.len() == 1);
+        let mut context: Context<u8> = config.new_context().map_err(|_| EncoderStatus::Failure)?;
+        let mut frame = context.new_frame();
+        for (plane, samples) in frame.planes.iter_mut().zip(planes) {
+            plane.copy_from_raw_u8(samples, width as usize, 1);
+        }
+        context.send_frame(frame)?;
+        context.flush();
+
+        let mut av1 = vec![];
+        loop {
+            match context.receive_packet() {
+                Ok(mut packet) => av1.append(&mut packet.data),
+                Err(EncoderStatus::Encoded) => continue,
+                Err(EncoderStatus::LimitReached) => return Ok(av1),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<W: Write> ImageEncoder for TiledAvifEncoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        if color_type != ExtendedColorType::Rgba8 {
+            return Err(ImageError::Unsupported(
+                UnsupportedError::from_format_and_kind(
+                    ImageFormat::Avif.into(),
+                    UnsupportedErrorKind::Color(color_type),
+                ),
+            ));
+        }
+
+        // Full range BT.601 YCbCr without subsampling, as ravif writes it
+        let pixels = || buf.chunks_exact(4);
+        let [mut y, mut cb, mut cr] = [(); 3].map(|_| Vec::with_capacity(buf.len() / 4));
+        for pixel in pixels() {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+            let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+            y.push(luma.round() as u8);
+            cb.push(((b - luma) / 1.772 + 128.0).round().clamp(0.0, 255.0) as u8);
+            cr.push(((r - luma) / 1.402 + 128.0).round().clamp(0.0, 255.0) as u8);
+        }
+        let alpha: Option<Vec<u8>> = pixels()
+            .any(|pixel| pixel[3] != 255)
+            .then(|| pixels().map(|pixel| pixel[3]).collect());
+
+        let av1_error = |e: rav1e::EncoderStatus| avif_error(&e.to_string());
+        let color = self
+            .encode_av1(width, height, &[y, cb, cr])
+            .map_err(av1_error)?;
+        let alpha = match alpha {
+            Some(alpha) => Some(
+                self.encode_av1(width, height, &[alpha])
+                    .map_err(av1_error)?,
+            ),
+            None => None,
+        };
+
+        let avif = avif_serialize::Aviffy::new()
+            .matrix_coefficients(avif_serialize::constants::MatrixCoefficients::Bt601)
+            .to_vec(&color, alpha.as_deref(), width, height, 8);
+        self.writer.write_all(&avif)?;
+        Ok(())
+    }
+}
+
+/// rav1e quantizer (`0` to `255`) for a quality from `1` to `100`, mapped the way ravif does so
+/// `q` means the same with and without tiles
+fn avif_quantizer(quality: u8) -> usize {
+    let q = f32::from(quality) / 100.0;
+    let x = match q {
+        q if q >= 0.85 => (1.0 - q) * 3.0,
+        q if q > 0.25 => 1.0 - 0.125 - q * 0.5,
+        q => 1.0 - q,
+    };
+    (x * 255.0).round() as usize
+}
+
+fn avif_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Avif),
+        message.to_string(),
+    ))
+}
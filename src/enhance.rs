@@ -0,0 +1,95 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use log::{error, warn};
+
+use crate::{ImageProviderError, ResizeConfig, Result};
+
+/// Client for the external service behind `?enhance=`, with a circuit breaker
+/// that stops calling it for a cooldown after repeated failures.
+pub(crate) struct Enhancer {
+    client: reqwest::Client,
+    url: String,
+    failure_threshold: u32,
+    cooldown: Duration,
+    breaker: Mutex<Breaker>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Enhancer {
+    pub(crate) fn new(config: &ResizeConfig) -> Option<Enhancer> {
+        let url = config.enhance_service_url.clone()?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.enhance_timeout_ms))
+            .build();
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Enhancement is disabled, its HTTP client failed to build: {e}");
+                return None;
+            }
+        };
+        Some(Enhancer {
+            client,
+            url,
+            failure_threshold: config.enhance_failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.enhance_cooldown_secs),
+            breaker: Mutex::new(Breaker::default()),
+        })
+    }
+
+    /// Post the encoded source, `mode` is passed along as the `mode` query parameter
+    pub(crate) async fn enhance(&self, source: Bytes, mime: &str, mode: &str) -> Result<Bytes> {
+        if let Some(open_until) = self.breaker.lock().unwrap().open_until {
+            if Instant::now() < open_until {
+                return Err(ImageProviderError::Unavailable(
+                    "Enhancement service is unavailable".to_string(),
+                ));
+            }
+        }
+
+        let response = self
+            .client
+            .post(&self.url)
+            .query(&[("mode", mode)])
+            .header(reqwest::header::CONTENT_TYPE, mime)
+            .body(source)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let result = match response {
+            Ok(response) => response.bytes().await,
+            Err(e) => Err(e),
+        };
+
+        let mut breaker = self.breaker.lock().unwrap();
+        match result {
+            Ok(bytes) => {
+                *breaker = Breaker::default();
+                Ok(bytes)
+            }
+            Err(e) => {
+                breaker.failures += 1;
+                if breaker.failures >= self.failure_threshold {
+                    warn!(
+                        "Enhancement service failed {} times, pausing",
+                        breaker.failures
+                    );
+                    breaker.failures = 0;
+                    breaker.open_until = Some(Instant::now() + self.cooldown);
+                }
+                Err(ImageProviderError::Upstream(format!(
+                    "Enhancement service failed: {e}"
+                )))
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::{error::Error, fmt, io};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use image::ImageError;
+use serde::Serialize;
+
+/// Machine readable `code` of an error response, stable across message wording changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    NotFound,
+    BadRequest,
+    UnsupportedFormat,
+    DecodeError,
+    EncodeError,
+    ResizeError,
+    IoError,
+    Upstream,
+    Unavailable,
+    Internal,
+    OutputTooLarge,
+    Forbidden,
+    UnsupportedMediaType,
+    SourceTooLarge,
+    RangeTooLarge,
+}
+
+/// Why a request failed, turned into a `{"code", "message"}` JSON response with a matching
+/// status code.
+#[derive(Debug)]
+pub enum ImageProviderError {
+    /// The path does not exist or escapes the root
+    NotFound,
+    /// A query parameter is invalid, or asks for a disabled feature
+    BadRequest(String),
+    /// The source or requested output is not a supported image format
+    UnsupportedFormat(String),
+    DecodeError(ImageError),
+    EncodeError(ImageError),
+    ResizeError(fast_image_resize::ResizeError),
+    IoError(io::Error),
+    /// The enhancement service failed or returned something unusable
+    Upstream(String),
+    /// A dependency is temporarily unavailable
+    Unavailable(String),
+    /// Any other failure while processing
+    Internal(String),
+    /// The encoded output is above `output_size_limit`
+    OutputTooLarge {
+        bytes: usize,
+        limit: u64,
+    },
+    /// The request is not signed with the configured secret
+    Forbidden(String),
+    /// The source is a variant of a supported format that cannot be processed yet
+    UnsupportedMediaType(String),
+    /// The source file is above `max_source_file_bytes`
+    SourceTooLarge {
+        bytes: u64,
+        limit: u64,
+    },
+    /// The `Range` header asks for more than `max_range_size` bytes
+    RangeTooLarge {
+        bytes: u64,
+        limit: u64,
+    },
+}
+
+impl ImageProviderError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ImageProviderError::NotFound => ErrorCode::NotFound,
+            ImageProviderError::BadRequest(_) => ErrorCode::BadRequest,
+            ImageProviderError::UnsupportedFormat(_) => ErrorCode::UnsupportedFormat,
+            ImageProviderError::DecodeError(_) => ErrorCode::DecodeError,
+            ImageProviderError::EncodeError(_) => ErrorCode::EncodeError,
+            ImageProviderError::ResizeError(_) => ErrorCode::ResizeError,
+            ImageProviderError::IoError(_) => ErrorCode::IoError,
+            ImageProviderError::Upstream(_) => ErrorCode::Upstream,
+            ImageProviderError::Unavailable(_) => ErrorCode::Unavailable,
+            ImageProviderError::Internal(_) => ErrorCode::Internal,
+            ImageProviderError::OutputTooLarge { .. } => ErrorCode::OutputTooLarge,
+            ImageProviderError::Forbidden(_) => ErrorCode::Forbidden,
+            ImageProviderError::UnsupportedMediaType(_) => ErrorCode::UnsupportedMediaType,
+            ImageProviderError::SourceTooLarge { .. } => ErrorCode::SourceTooLarge,
+            ImageProviderError::RangeTooLarge { .. } => ErrorCode::RangeTooLarge,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ImageProviderError::NotFound => StatusCode::NOT_FOUND,
+            ImageProviderError::BadRequest(_) | ImageProviderError::UnsupportedFormat(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ImageProviderError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ImageProviderError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ImageProviderError::OutputTooLarge { .. }
+            | ImageProviderError::SourceTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ImageProviderError::RangeTooLarge { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+            ImageProviderError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ImageProviderError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            // A corrupt source is usually a broken upload, one above the decoder limits too large
+            ImageProviderError::DecodeError(ImageError::Decoding(_)) => StatusCode::BAD_REQUEST,
+            ImageProviderError::DecodeError(ImageError::IoError(e))
+                if e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                StatusCode::BAD_REQUEST
+            }
+            ImageProviderError::DecodeError(ImageError::Limits(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            ImageProviderError::DecodeError(_)
+            | ImageProviderError::EncodeError(_)
+            | ImageProviderError::ResizeError(_)
+            | ImageProviderError::IoError(_)
+            | ImageProviderError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for ImageProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageProviderError::NotFound => f.write_str("File not found"),
+            ImageProviderError::UnsupportedFormat(format) => {
+                write!(f, "Unsupported format: {format}")
+            }
+            ImageProviderError::DecodeError(e) => write!(f, "Failed to decode image: {e}"),
+            ImageProviderError::EncodeError(e) => write!(f, "Failed to encode image: {e}"),
+            ImageProviderError::ResizeError(e) => write!(f, "Failed to resize image: {e}"),
+            ImageProviderError::IoError(e) => write!(f, "Failed to read image: {e}"),
+            ImageProviderError::OutputTooLarge { bytes, limit } => {
+                write!(f, "Output is {bytes} bytes, above the {limit} byte limit")
+            }
+            ImageProviderError::SourceTooLarge { bytes, limit } => {
+                write!(f, "Source is {bytes} bytes, above the {limit} byte limit")
+            }
+            ImageProviderError::RangeTooLarge { bytes, limit } => {
+                write!(
+                    f,
+                    "Range asks for {bytes} bytes, above the {limit} byte limit"
+                )
+            }
+            ImageProviderError::BadRequest(message)
+            | ImageProviderError::Upstream(message)
+            | ImageProviderError::Unavailable(message)
+            | ImageProviderError::Internal(message)
+            | ImageProviderError::Forbidden(message)
+            | ImageProviderError::UnsupportedMediaType(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for ImageProviderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImageProviderError::DecodeError(e) | ImageProviderError::EncodeError(e) => Some(e),
+            ImageProviderError::ResizeError(e) => Some(e),
+            ImageProviderError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Why an [`ImageQuery`](crate::ImageQuery) was rejected before any processing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageQueryError {
+    /// `dpr` is not a number within the supported range
+    Dpr(String),
+    /// `w` or `h` is zero
+    ZeroSize(&'static str),
+    /// `output` is not a known format
+    Output(String),
+    /// `q` (or `quality`) is outside `1` to `100`
+    Quality(u8),
+}
+
+impl fmt::Display for ImageQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageQueryError::Dpr(dpr) => write!(
+                f,
+                "Unsupported dpr: {dpr} (expected a number from {})",
+                crate::MIN_DPR
+            ),
+            ImageQueryError::ZeroSize(param) => write!(f, "{param} must be above 0"),
+            ImageQueryError::Output(format) => write!(f, "Unsupported format: {format}"),
+            ImageQueryError::Quality(q) => write!(f, "q must be between 1 and 100, got {q}"),
+        }
+    }
+}
+
+impl Error for ImageQueryError {}
+
+impl From<ImageQueryError> for ImageProviderError {
+    fn from(e: ImageQueryError) -> Self {
+        match e {
+            ImageQueryError::Output(format) => ImageProviderError::UnsupportedFormat(format),
+            e => ImageProviderError::BadRequest(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for ImageProviderError {
+    fn from(e: io::Error) -> Self {
+        ImageProviderError::IoError(e)
+    }
+}
+
+impl From<fast_image_resize::ResizeError> for ImageProviderError {
+    fn from(e: fast_image_resize::ResizeError) -> Self {
+        ImageProviderError::ResizeError(e)
+    }
+}
+
+impl IntoResponse for ImageProviderError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+        };
+        let mut response = (self.status_code(), Json(body)).into_response();
+        if let ImageProviderError::OutputTooLarge { bytes, .. } = self {
+            response
+                .headers_mut()
+                .insert("x-encoded-bytes", bytes.into());
+        }
+        response
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+}
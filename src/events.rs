@@ -0,0 +1,21 @@
+use std::path::Path;
+
+use crate::{ImageProviderError, ImageQuery};
+
+/// Hooks into the image processing lifecycle, e.g. for metrics or alerting.
+///
+/// Every method does nothing by default, implement only the events you need.
+/// Handlers are called inline on the request, so keep them cheap.
+pub trait ImageEventHandler: Send + Sync {
+    /// A processed image was not cached and is about to be generated
+    fn on_cache_miss(&self, _path: &Path, _query: &ImageQuery) {}
+
+    /// A processed image was served from the cache
+    fn on_cache_hit(&self, _path: &Path, _query: &ImageQuery) {}
+
+    /// An image was processed and encoded into `bytes` bytes
+    fn on_encode_complete(&self, _path: &Path, _bytes: usize, _elapsed_ms: u64) {}
+
+    /// A request failed, `path` is the requested path relative to the root
+    fn on_error(&self, _path: &Path, _error: &ImageProviderError) {}
+}
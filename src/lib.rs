@@ -1,243 +1,3470 @@
-use std::{f32, io::Cursor, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    f32,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufReader, Cursor},
+    net::{IpAddr, SocketAddr},
+    ops::Bound,
+    path::PathBuf,
+    sync::Arc,
+    time::Instant,
+};
 
 use axum::{
-    extract::{Path, Query, State},
+    extract::{
+        multipart::MultipartError, ConnectInfo, DefaultBodyLimit, Multipart, OriginalUri, Path,
+        Query, State,
+    },
     http::{
-        header::{CACHE_CONTROL, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+        header::{
+            ACCEPT, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY,
+            X_CONTENT_TYPE_OPTIONS,
+        },
         HeaderMap, HeaderValue, StatusCode,
     },
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{get, post},
+    Extension, Json, Router,
 };
-use axum_extra::{headers::Range, TypedHeader};
-use axum_range::{KnownSize, Ranged};
+use axum_extra::{
+    headers::{ETag, HeaderMapExt, IfNoneMatch, Range},
+    TypedHeader,
+};
+use axum_range::{KnownSize, RangeBody, Ranged};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use brotli::enc::BrotliEncoderParams;
 use bytes::Bytes;
 use cached::{Cached, TimedSizedCache};
-use fast_image_resize::{images::Image, IntoImageView, ResizeAlg, ResizeOptions, Resizer};
+use dashmap::{mapref::entry::Entry, DashMap};
+use derive_builder::Builder;
+use encoder::WebPEncoder;
+#[cfg(feature = "enhance")]
+use enhance::Enhancer;
+use exoquant::{
+    ditherer::{self, Ditherer},
+    generate_palette, optimizer, Color, Remapper, SimpleColorSpace,
+};
+use fast_image_resize::{FilterType, ResizeAlg, ResizeOptions, Resizer};
 use image::{
-    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
-    load_from_memory, ColorType, DynamicImage, ImageEncoder, ImageFormat,
+    codecs::{
+        avif::AvifEncoder,
+        gif::{GifDecoder, GifEncoder},
+        ico::{IcoEncoder, IcoFrame},
+        jpeg::JpegEncoder,
+        png::{PngDecoder, PngEncoder},
+        tiff::TiffEncoder,
+        webp::WebPDecoder,
+    },
+    imageops, load_from_memory_with_format, AnimationDecoder, DynamicImage, GrayImage,
+    ImageEncoder, ImageFormat, ImageReader, Rgba, RgbaImage,
 };
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use mime_guess::MimeGuess;
-use serde::Deserialize;
-use tokio::{fs::File, io::AsyncReadExt, sync::Mutex};
+use request_metrics::Metrics;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::{broadcast, Mutex, OnceCell},
+};
+use tokio_util::io::SyncIoBridge;
 
 pub mod config;
+mod encoder;
+#[cfg(feature = "enhance")]
+mod enhance;
+pub mod error;
+pub mod events;
+mod quality;
+mod request_metrics;
+mod signature;
 
 pub use config::*;
+pub use error::*;
+pub use events::*;
+pub use signature::{sign, sign_url};
+
+pub fn get_images_router(config: ResizeConfig) -> Router {
+    images_router(config, None, HashMap::new())
+}
+
+/// Same as [`get_images_router`], reporting lifecycle events to `handler`
+pub fn get_images_router_with_events(
+    config: ResizeConfig,
+    handler: Arc<dyn ImageEventHandler>,
+) -> Router {
+    images_router(config, Some(handler), HashMap::new())
+}
+
+/// Same as [`get_images_router`], with every route nested under `prefix` (e.g. `/images`)
+///
+/// # Panics
+///
+/// If `prefix` does not start with `/` or ends with `/`
+pub fn get_images_router_with_prefix(config: ResizeConfig, prefix: &str) -> Router {
+    assert!(
+        prefix.starts_with('/') && !prefix.ends_with('/'),
+        "Invalid images router prefix {prefix:?}: it must start with `/` and not end with `/`"
+    );
+    Router::new().nest(prefix, images_router(config, None, HashMap::new()))
+}
+
+/// Serve several directories from one router, `GET /{mount_name}/{*path}` reads `path` from the
+/// directory mounted as `mount_name`. `config.root` is ignored, as are `/info` and
+/// `/favicon.ico`. Every mount shares the cache and limits of `config`
+///
+/// # Panics
+///
+/// If a mount name is empty, contains `/` or is used twice
+pub fn get_images_router_multi(roots: Vec<(String, PathBuf)>, config: ResizeConfig) -> Router {
+    let mut mounts = HashMap::new();
+    for (name, root) in roots {
+        assert!(
+            !name.is_empty() && !name.contains('/'),
+            "Invalid mount name {name:?}: it must be non-empty and not contain `/`"
+        );
+        assert!(
+            mounts.insert(name.clone(), root).is_none(),
+            "Mount name {name:?} is used twice"
+        );
+    }
+    images_router(config, None, mounts)
+}
 
-pub fn get_images_router(root: PathBuf, config: ResizeConfig) -> Router {
-    const CACHE_LIFESPAN: u64 = 24 * 60 * 60; // 1 days in seconds
+fn images_router(
+    config: ResizeConfig,
+    events: Option<Arc<dyn ImageEventHandler>>,
+    mounts: HashMap<String, PathBuf>,
+) -> Router {
     let cache = TimedSizedCache::with_size_and_lifespan_and_refresh(
         config.cache_size,
-        CACHE_LIFESPAN,
-        true,
+        config.cache_lifespan_secs,
+        config.cache_refresh,
     );
     let cache = Arc::new(Mutex::new(cache));
+    let cors = config.cors_layer();
+    #[cfg(feature = "enhance")]
+    let enhancer = Enhancer::new(&config).map(Arc::new);
 
-    Router::new()
-        .route("/{*path}", get(provide_images))
-        .route(
-            "/",
-            get(|| async { (StatusCode::NOT_FOUND, "File not found".to_string()) }),
-        )
-        .with_state(ImageState {
-            root,
-            config,
-            cache,
-        })
+    let mut router = Router::new();
+    if !config.health_path.is_empty() {
+        router = router.route(&config.health_path, get(|| async { "ok" }));
+    }
+    if !config.readiness_path.is_empty() {
+        router = router.route(&config.readiness_path, get(provide_readiness));
+    }
+    if config.enable_admin_endpoints {
+        router = router
+            .route("/__cache/stats", get(provide_cache_stats))
+            .route("/__cache/clear", post(clear_cache))
+            .route("/metrics", get(provide_metrics));
+    }
+    if config.enable_upload {
+        // Limited by max_source_file_bytes while reading instead
+        let upload = post(provide_upload).layer(DefaultBodyLimit::disable());
+        router = router.route("/upload", upload);
+    }
+
+    router = match mounts.is_empty() {
+        true => router
+            .route("/favicon.ico", get(provide_favicon))
+            .route("/info/{*path}", get(provide_info))
+            .route("/{*path}", get(provide_images)),
+        false => router.route("/{mount}/{*path}", get(provide_mounted_images)),
+    };
+    router = match config.allow_directory_index && mounts.is_empty() {
+        true => router.route("/", get(provide_index)),
+        false => router.route("/", get(|| async { ImageProviderError::NotFound })),
+    };
+
+    let router = router.with_state(ImageState {
+        root: config.root.clone(),
+        mounts: Arc::new(mounts),
+        config,
+        cache,
+        favicon: Arc::new(OnceCell::new()),
+        events,
+        in_flight: Arc::new(DashMap::new()),
+        #[cfg(feature = "enhance")]
+        enhancer,
+        metrics: Arc::new(Metrics::default()),
+    });
+
+    match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
 }
 
-type Error = (StatusCode, String);
-type Result<T, E = Error> = std::result::Result<T, E>;
+/// Source path, query and (with `per_ip_cache`) the client address
+type CacheKey = (PathBuf, ImageQuery, Option<IpAddr>);
+
+type Result<T, E = ImageProviderError> = std::result::Result<T, E>;
+
+/// Record fields on the request span, a no-op without the `tracing` feature
+macro_rules! record_span {
+    ($($field: literal = $value: expr),+ $(,)?) => {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::Span::current();
+            $(span.record($field, tracing::field::debug(&$value));)+
+        }
+    };
+}
 
 #[derive(Clone)]
 struct ImageState {
     root: PathBuf,
+    /// Named roots of [`get_images_router_multi`], a request's mount replaces `root`
+    mounts: Arc<HashMap<String, PathBuf>>,
     config: ResizeConfig,
-    cache: Arc<Mutex<TimedSizedCache<(PathBuf, ImageQuery), Bytes>>>,
+    cache: Arc<Mutex<TimedSizedCache<CacheKey, ProcessedImage>>>,
+    /// Generated favicon, kept for the lifetime of the router
+    favicon: Arc<OnceCell<Bytes>>,
+    events: Option<Arc<dyn ImageEventHandler>>,
+    in_flight: Arc<InFlight>,
+    /// Client for `?enhance=`, when `enhance_service_url` is configured
+    #[cfg(feature = "enhance")]
+    enhancer: Option<Arc<Enhancer>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Encoded image along with the headers that describe it
+#[derive(Clone)]
+struct ProcessedImage {
+    bytes: Bytes,
+    headers: HeaderMap,
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+/// Lowest accepted `dpr`, the highest is `max_dpr`
+pub const MIN_DPR: f32 = 0.5;
+
+/// Output names accepted besides image extensions
+const OUTPUT_KEYWORDS: [&str; 7] = [
+    "ascii",
+    "auto",
+    "thumbnail_strip",
+    "raw",
+    "jpeg2000",
+    "jp2",
+    "j2k",
+];
+
+/// Parameters of an image request, deserialized from the query string or built with
+/// [`ImageQuery::builder`]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, Hash, Builder)]
+#[builder(
+    pattern = "owned",
+    default,
+    setter(into, strip_option),
+    build_fn(private, name = "fill")
+)]
 pub struct ImageQuery {
     pub output: Option<String>,
     pub dpr: Option<String>,
     pub w: Option<u32>,
     pub h: Option<u32>,
     pub ce: Option<String>,
+    /// Region `x,y,w,h` (in source pixels) to cut out before resizing
+    pub crop: Option<String>,
+    /// Margins `top,right,bottom,left` to cut off before resizing, in percent of the source size
+    pub crop_pct: Option<String>,
+    /// Convert to grayscale before resizing
+    pub gray: Option<String>,
+    /// Mirror before resizing: `h` (horizontally), `v` (vertically) or `hv` (both)
+    pub flip: Option<String>,
+    /// Rotate clockwise before resizing: `90`, `180` or `270`
+    pub rotate: Option<u32>,
+    /// How the source fills `w` x `h`: `cover` (default, crop), `contain` (shrink the output
+    /// to the source aspect ratio) or `fill` (stretch)
+    pub fit: Option<String>,
+    /// Part of the image kept by `fit=cover`: `center` (default), `top`, `bottom`, `left`,
+    /// `right`, `top-left`, `top-right`, `bottom-left` or `bottom-right`
+    pub anchor: Option<String>,
+    /// WebP compression effort `0` (fastest) to `6` (smallest), defaults to `4`.
+    /// `6` can take 10x longer than `4`, so rate-limit clients that use it
+    pub webp_method: Option<u8>,
+    /// PNG compression `default`, `fast` or `best`, overrides `png_compression`
+    pub compress: Option<String>,
+    /// Lossy quality `1` to `100` for JPEG (default `80`), AVIF (default `60`) and WebP
+    /// (lossless when omitted), ignored by lossless formats. Also accepted as `quality`
+    #[serde(alias = "quality")]
+    pub q: Option<u8>,
+    pub lossless: Option<String>,
+    /// Keep the RGB values under fully transparent pixels, requires `lossless`
+    pub webp_exact: Option<String>,
+    /// Encode WebP lossy with the alpha channel at this quality (`0` to `100`)
+    pub webp_alpha_quality: Option<u8>,
+    /// WebP segments `1` to `4` (default), each region class gets its own quality settings.
+    /// Fewer segments encode faster but spread quality less evenly over complex images
+    pub webp_segments: Option<u8>,
+    /// Decode a JPEG source at 1/8 size from its DC coefficients only, for a fast preview.
+    /// Requires JPEG output and the `mozjpeg` feature
+    pub dc_only: Option<String>,
+    /// Send the source through the enhancement service first: `auto`, `faces`, `landscape` or
+    /// `food`, requires `enhance_service_url`
+    pub enhance: Option<String>,
+    /// AVIF encoding speed `1` (slowest, smallest) to `10` (fastest), defaults to `avif_speed`
+    pub avif_speed: Option<u8>,
+    /// AVIF encoder, one of [`AVIF_CODECS`]. Others (e.g. `libaom`, `svt`) are rejected
+    pub avif_codec: Option<String>,
+    /// AVIF tiles `<cols>x<rows>`, up to `64x64`. Each is rounded up to a power of two and
+    /// clamped to the superblocks of the image
+    pub avif_tiles: Option<String>,
+    /// Color behind transparent pixels when encoding JPEG: hex (`fff`, `#ffffff`) or a CSS
+    /// color name, defaults to white
+    pub bg: Option<String>,
+    /// Encode JPEG with mozjpeg and optimized Huffman tables, requires the `mozjpeg` feature
+    pub jpeg_huffman_optimize: Option<String>,
+    /// JPEG 2000 compression ratio (e.g. `20` for 20:1), lossless when omitted
+    pub j2k_quality: Option<u32>,
+    /// Reject encoded outputs above this many bytes with 413, capped by `output_size_limit`
+    pub output_size_limit: Option<u64>,
+    /// Browser cache TTL in seconds, requires `allow_cache_control_override`
+    pub max_age: Option<u64>,
+    /// CDN cache TTL in seconds, requires `allow_cache_control_override`
+    #[serde(alias = "s-maxage")]
+    pub s_maxage: Option<u64>,
+    /// Pixel layout for `output=raw`: `rgba8` (default), `rgb8` or `gray8`
+    pub pixel_format: Option<String>,
+    /// Split into `<cols>x<rows>` tiles, requires `allow_grid_split`
+    pub grid: Option<String>,
+    /// Column of the XYZ map tile to return, requires `tile_y` and `tile_zoom`
+    pub tile_x: Option<u32>,
+    /// Row of the XYZ map tile to return, requires `tile_x` and `tile_zoom`
+    pub tile_y: Option<u32>,
+    /// Zoom level of the XYZ map tile, the source is split into `2^tile_zoom` tiles per side
+    pub tile_zoom: Option<u32>,
+    /// Comma separated paths (relative to root) to concatenate after this image
+    pub stitch: Option<String>,
+    /// `h` (default) to stitch side by side, `v` to stack
+    pub stitch_direction: Option<String>,
+    /// Path (relative to root) of an image to subtract, returns `|A - B|` per color channel
+    pub diff: Option<String>,
+    /// Path (relative to root) of a PNG or WebP overlaid on the bottom-right corner after
+    /// resizing, sized and faded by `watermark_scale` and `watermark_opacity`
+    pub watermark: Option<String>,
+    /// Add an `X-PSNR-dB` header comparing the output to the source, requires `allow_psnr`
+    pub psnr: Option<String>,
+    /// Add an `X-SSIM` header comparing the output to the source, requires `allow_ssim`
+    pub ssim: Option<String>,
+    /// Comma separated image formats to encode, the smallest result is returned
+    pub output_compare: Option<String>,
+    /// Comma separated image formats to try in order, the first that encodes is returned
+    pub output_format_chain: Option<String>,
+    /// `fast` or `quality`, overrides `fast_thumbnail` for this request
+    pub sampling: Option<String>,
+    /// Comma separated `<w>x<h>` sizes to downscale through before the final size
+    pub chained_resize: Option<String>,
+    /// Reduce the output to a palette of `2` to `256` colors
+    pub max_colors: Option<u16>,
+    /// `none` (default), `floyd-steinberg` or `ordered`, requires `max_colors`
+    pub dither: Option<String>,
+    /// Quantize to 256 colors with libimagequant at this quality (`0` to `100`), PNG output is
+    /// written with a palette. Requires the `pngquant` feature
+    pub quantize: Option<u8>,
+    /// Pixelate the output into square tiles of this many pixels
+    pub mosaic: Option<u32>,
+    /// Gaussian blur sigma applied after resizing, above `0`
+    pub blur: Option<String>,
+    /// Unsharp mask sigma applied after resizing, above `0`. Ignored with `blur`
+    pub sharpen: Option<String>,
+    /// Halve the image `1` to `6` times with a gaussian blur, ignoring `w`/`h`/`dpr`
+    pub gaussian_pyramid: Option<u8>,
+    /// Apply the configured thumbnail preset, explicit parameters still take precedence
+    pub thumb: Option<String>,
+    /// Return a placeholder at 1% of the source size as a `data:image/jpeg;base64,...` URL,
+    /// ignoring `w`/`h`
+    pub lqip: Option<String>,
+    /// Return a single `r`, `g`, `b` or `a` channel as grayscale
+    pub channel: Option<String>,
+    /// `auto` for gray world, or the light's color temperature in Kelvin (`1000` to `40000`)
+    pub white_balance: Option<String>,
+    /// Number of frames in `output=thumbnail_strip`, up to `max_strip_count`
+    pub count: Option<usize>,
+    /// Seconds between frames in `output=thumbnail_strip`, spread evenly when omitted
+    pub interval: Option<String>,
+    /// Refuse the request unless its parameters are verified by exactly one `sig`, even on a
+    /// server without a `secret`
+    pub signed_operations: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dither {
+    None,
+    FloydSteinberg,
+    Ordered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fit {
+    /// Crop the source to the output aspect ratio, keeping the part at `(x, y)`
+    Cover(f64, f64),
+    /// Shrink the output to the source aspect ratio
+    Contain,
+    /// Stretch the source to the output, ignoring the aspect ratio
+    Fill,
+}
+
+impl Fit {
+    /// Centering for `fit_into_destination`, `None` stretches
+    fn centering(self) -> Option<(f64, f64)> {
+        match self {
+            Fit::Cover(x, y) => Some((x, y)),
+            Fit::Contain => Some((0.5, 0.5)),
+            Fit::Fill => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StitchDirection {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Image(ImageFormat),
+    Ascii,
+    Jpeg2000,
+    Raw(PixelFormat),
+    /// Tiny JPEG placeholder as a `data:` URL
+    Lqip,
+    /// WebP, PNG or JPEG, picked from the image content once it is resized
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgba8,
+    Rgb8,
+    Gray8,
+}
+
+impl PixelFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PixelFormat::Rgba8 => "rgba8",
+            PixelFormat::Rgb8 => "rgb8",
+            PixelFormat::Gray8 => "gray8",
+        }
+    }
+}
+
+impl OutputFormat {
+    fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Image(format) => format.to_mime_type(),
+            OutputFormat::Ascii => "text/plain; charset=utf-8",
+            OutputFormat::Jpeg2000 => "image/jp2",
+            OutputFormat::Raw(_) => "application/octet-stream",
+            OutputFormat::Lqip => "text/plain; charset=utf-8",
+            // Replaced by the picked format's type after encoding
+            OutputFormat::Auto => "application/octet-stream",
+        }
+    }
+}
+
+impl ImageQueryBuilder {
+    /// Build the query, failing on the same parameters a request would be rejected for
+    pub fn build(self) -> Result<ImageQuery, ImageQueryError> {
+        let query = self.fill().expect("every ImageQuery field has a default");
+        query.validate()?;
+        Ok(query)
+    }
 }
 
 impl ImageQuery {
-    fn output(&self) -> Result<Option<ImageFormat>> {
+    pub fn builder() -> ImageQueryBuilder {
+        ImageQueryBuilder::default()
+    }
+
+    /// Check the parameters that apply to every request, before touching the source
+    pub fn validate(&self) -> Result<(), ImageQueryError> {
+        if let Some(dpr) = &self.dpr {
+            match dpr.parse::<f32>() {
+                Ok(value) if value.is_finite() && value >= MIN_DPR => {}
+                _ => return Err(ImageQueryError::Dpr(dpr.clone())),
+            }
+        }
+        if self.w == Some(0) {
+            return Err(ImageQueryError::ZeroSize("w"));
+        }
+        if self.h == Some(0) {
+            return Err(ImageQueryError::ZeroSize("h"));
+        }
+        if let Some(q) = self.q.filter(|q| !(1..=100).contains(q)) {
+            return Err(ImageQueryError::Quality(q));
+        }
+        if let Some(output) = &self.output {
+            if !OUTPUT_KEYWORDS.contains(&output.as_str())
+                && find_image_mime(MimeGuess::from_ext(output)).is_none()
+            {
+                return Err(ImageQueryError::Output(output.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill every unset parameter from `defaults`
+    fn or(self, defaults: ImageQuery) -> ImageQuery {
+        ImageQuery {
+            output: self.output.or(defaults.output),
+            dpr: self.dpr.or(defaults.dpr),
+            w: self.w.or(defaults.w),
+            h: self.h.or(defaults.h),
+            ce: self.ce.or(defaults.ce),
+            crop: self.crop.or(defaults.crop),
+            crop_pct: self.crop_pct.or(defaults.crop_pct),
+            gray: self.gray.or(defaults.gray),
+            flip: self.flip.or(defaults.flip),
+            rotate: self.rotate.or(defaults.rotate),
+            fit: self.fit.or(defaults.fit),
+            anchor: self.anchor.or(defaults.anchor),
+            webp_method: self.webp_method.or(defaults.webp_method),
+            compress: self.compress.or(defaults.compress),
+            q: self.q.or(defaults.q),
+            lossless: self.lossless.or(defaults.lossless),
+            webp_exact: self.webp_exact.or(defaults.webp_exact),
+            webp_alpha_quality: self.webp_alpha_quality.or(defaults.webp_alpha_quality),
+            webp_segments: self.webp_segments.or(defaults.webp_segments),
+            dc_only: self.dc_only.or(defaults.dc_only),
+            enhance: self.enhance.or(defaults.enhance),
+            avif_speed: self.avif_speed.or(defaults.avif_speed),
+            avif_codec: self.avif_codec.or(defaults.avif_codec),
+            avif_tiles: self.avif_tiles.or(defaults.avif_tiles),
+            bg: self.bg.or(defaults.bg),
+            jpeg_huffman_optimize: self
+                .jpeg_huffman_optimize
+                .or(defaults.jpeg_huffman_optimize),
+            j2k_quality: self.j2k_quality.or(defaults.j2k_quality),
+            output_size_limit: self.output_size_limit.or(defaults.output_size_limit),
+            max_age: self.max_age.or(defaults.max_age),
+            s_maxage: self.s_maxage.or(defaults.s_maxage),
+            pixel_format: self.pixel_format.or(defaults.pixel_format),
+            grid: self.grid.or(defaults.grid),
+            tile_x: self.tile_x.or(defaults.tile_x),
+            tile_y: self.tile_y.or(defaults.tile_y),
+            tile_zoom: self.tile_zoom.or(defaults.tile_zoom),
+            stitch: self.stitch.or(defaults.stitch),
+            stitch_direction: self.stitch_direction.or(defaults.stitch_direction),
+            diff: self.diff.or(defaults.diff),
+            watermark: self.watermark.or(defaults.watermark),
+            psnr: self.psnr.or(defaults.psnr),
+            ssim: self.ssim.or(defaults.ssim),
+            output_compare: self.output_compare.or(defaults.output_compare),
+            output_format_chain: self.output_format_chain.or(defaults.output_format_chain),
+            sampling: self.sampling.or(defaults.sampling),
+            chained_resize: self.chained_resize.or(defaults.chained_resize),
+            max_colors: self.max_colors.or(defaults.max_colors),
+            dither: self.dither.or(defaults.dither),
+            quantize: self.quantize.or(defaults.quantize),
+            mosaic: self.mosaic.or(defaults.mosaic),
+            blur: self.blur.or(defaults.blur),
+            sharpen: self.sharpen.or(defaults.sharpen),
+            gaussian_pyramid: self.gaussian_pyramid.or(defaults.gaussian_pyramid),
+            thumb: self.thumb.or(defaults.thumb),
+            lqip: self.lqip.or(defaults.lqip),
+            channel: self.channel.or(defaults.channel),
+            white_balance: self.white_balance.or(defaults.white_balance),
+            count: self.count.or(defaults.count),
+            interval: self.interval.or(defaults.interval),
+            signed_operations: self.signed_operations.or(defaults.signed_operations),
+        }
+    }
+
+    fn output(&self) -> Result<Option<OutputFormat>> {
+        if flag(&self.lqip) {
+            return match self.output {
+                Some(_) => Err(ImageProviderError::BadRequest(
+                    "lqip cannot be combined with output".to_string(),
+                )),
+                None => Ok(Some(OutputFormat::Lqip)),
+            };
+        }
+
         self.output
             .as_ref()
-            .map(|ext| {
-                find_image_mime(MimeGuess::from_ext(ext)).ok_or((
-                    StatusCode::BAD_REQUEST,
-                    format!("Unsupported output format: {ext}"),
-                ))
+            .map(|ext| match ext.as_str() {
+                "ascii" => Ok(OutputFormat::Ascii),
+                "auto" => Ok(OutputFormat::Auto),
+                "thumbnail_strip" => Ok(OutputFormat::Image(ImageFormat::Png)),
+                "raw" => self.pixel_format().map(OutputFormat::Raw),
+                "jpeg2000" | "jp2" | "j2k" if cfg!(feature = "jpeg2000") => {
+                    Ok(OutputFormat::Jpeg2000)
+                }
+                "jpeg2000" | "jp2" | "j2k" => Err(ImageProviderError::BadRequest(
+                    "JPEG 2000 output is unavailable, compile with --features jpeg2000".to_string(),
+                )),
+                _ => find_image_mime(MimeGuess::from_ext(ext))
+                    .map(OutputFormat::Image)
+                    .ok_or_else(|| ImageProviderError::UnsupportedFormat(ext.to_string())),
             })
             .transpose()
     }
 
-    fn size(&self) -> (Option<u32>, Option<u32>) {
-        (self.w, self.h)
+    fn output_compare(&self) -> Result<Option<Vec<(&str, ImageFormat)>>> {
+        self.output_compare
+            .as_deref()
+            .map(parse_formats)
+            .transpose()
+    }
+
+    fn output_format_chain(&self) -> Result<Option<Vec<(&str, ImageFormat)>>> {
+        self.output_format_chain
+            .as_deref()
+            .map(parse_formats)
+            .transpose()
+    }
+
+    /// Whether to prescale with bilinear before the final resize
+    fn fast_sampling(&self, config: &ResizeConfig) -> Result<bool> {
+        match self.sampling.as_deref() {
+            None => Ok(config.fast_thumbnail),
+            Some("fast") => Ok(true),
+            Some("quality") => Ok(false),
+            Some(sampling) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported sampling: {sampling}"
+            ))),
+        }
+    }
+
+    fn pixel_format(&self) -> Result<PixelFormat> {
+        match self.pixel_format.as_deref() {
+            None | Some("rgba8") => Ok(PixelFormat::Rgba8),
+            Some("rgb8") => Ok(PixelFormat::Rgb8),
+            Some("gray8") => Ok(PixelFormat::Gray8),
+            Some(format) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported pixel format: {format}"
+            ))),
+        }
+    }
+
+    fn grid(&self) -> Result<Option<(u32, u32)>> {
+        let Some(grid) = self.grid.as_deref() else {
+            return Ok(None);
+        };
+
+        grid.split_once('x')
+            .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)))
+            .filter(|&(cols, rows)| cols > 0 && rows > 0)
+            .map(Some)
+            .ok_or(ImageProviderError::BadRequest(format!(
+                "Invalid grid: {grid} (expected <cols>x<rows>)"
+            )))
+    }
+
+    /// AVIF tile columns and rows of `avif_tiles`
+    fn avif_tiles(&self) -> Result<Option<(usize, usize)>> {
+        const MAX_TILES: usize = 64;
+        let Some(tiles) = self.avif_tiles.as_deref() else {
+            return Ok(None);
+        };
+
+        tiles
+            .split_once('x')
+            .and_then(|(cols, rows)| {
+                Some((cols.parse::<usize>().ok()?, rows.parse::<usize>().ok()?))
+            })
+            .filter(|&(cols, rows)| {
+                (1..=MAX_TILES).contains(&cols) && (1..=MAX_TILES).contains(&rows)
+            })
+            .map(Some)
+            .ok_or(ImageProviderError::BadRequest(format!(
+                "Invalid avif_tiles: {tiles} (expected <cols>x<rows>, each 1-{MAX_TILES})"
+            )))
+    }
+
+    /// Crop region as `(x, y, width, height)`, checked against the source once it is loaded
+    fn crop(&self) -> Result<Option<(u32, u32, u32, u32)>> {
+        let Some(crop) = self.crop.as_deref() else {
+            return Ok(None);
+        };
+
+        let values = crop
+            .split(',')
+            .map(|value| value.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>();
+        match values.as_deref() {
+            Ok(&[x, y, width, height]) if width > 0 && height > 0 => {
+                Ok(Some((x, y, width, height)))
+            }
+            _ => Err(ImageProviderError::BadRequest(format!(
+                "Invalid crop: {crop} (expected x,y,w,h)"
+            ))),
+        }
+    }
+
+    /// Crop margins as `[top, right, bottom, left]` percentages
+    fn crop_pct(&self) -> Result<Option<[f32; 4]>> {
+        let Some(crop_pct) = self.crop_pct.as_deref() else {
+            return Ok(None);
+        };
+        if self.crop.is_some() {
+            return Err(ImageProviderError::BadRequest(
+                "crop_pct cannot be combined with crop".to_string(),
+            ));
+        }
+
+        let values = crop_pct
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>();
+        match values.as_deref() {
+            Ok(&[top, right, bottom, left])
+                if [top, right, bottom, left]
+                    .iter()
+                    .all(|pct| (0.0..=100.0).contains(pct)) =>
+            {
+                Ok(Some([top, right, bottom, left]))
+            }
+            _ => Err(ImageProviderError::BadRequest(format!(
+                "Invalid crop_pct: {crop_pct} (expected top,right,bottom,left between 0 and 100)"
+            ))),
+        }
+    }
+
+    /// Whether to mirror `(horizontally, vertically)`
+    fn flip(&self) -> Result<(bool, bool)> {
+        match self.flip.as_deref() {
+            None => Ok((false, false)),
+            Some("h") => Ok((true, false)),
+            Some("v") => Ok((false, true)),
+            Some("hv") | Some("vh") => Ok((true, true)),
+            Some(flip) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported flip: {flip} (expected h, v or hv)"
+            ))),
+        }
+    }
+
+    fn rotate(&self) -> Result<Option<u32>> {
+        match self.rotate {
+            None => Ok(None),
+            Some(degrees @ (90 | 180 | 270)) => Ok(Some(degrees)),
+            Some(degrees) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported rotate: {degrees} (expected 90, 180 or 270)"
+            ))),
+        }
+    }
+
+    /// XYZ map tile as `(zoom, x, y)`
+    fn tile(&self) -> Result<Option<(u32, u32, u32)>> {
+        match (self.tile_zoom, self.tile_x, self.tile_y) {
+            (None, None, None) => Ok(None),
+            (Some(zoom @ 0..=MAX_TILE_ZOOM), Some(x), Some(y)) => {
+                if x >> zoom != 0 || y >> zoom != 0 {
+                    return Err(ImageProviderError::BadRequest(format!(
+                        "Tile {x}/{y} is outside zoom level {zoom}"
+                    )));
+                }
+                Ok(Some((zoom, x, y)))
+            }
+            (Some(zoom), Some(_), Some(_)) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported tile_zoom: {zoom} (expected 0-{MAX_TILE_ZOOM})"
+            ))),
+            _ => Err(ImageProviderError::BadRequest(
+                "tile_x, tile_y and tile_zoom must be used together".to_string(),
+            )),
+        }
+    }
+
+    fn chained_resize(&self, config: &ResizeConfig) -> Result<Vec<(u32, u32)>> {
+        let Some(chain) = self.chained_resize.as_deref() else {
+            return Ok(vec![]);
+        };
+
+        let steps = chain
+            .split(',')
+            .map(|step| {
+                step.split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .filter(|&(w, h)| w > 0 && h > 0)
+                    .ok_or(ImageProviderError::BadRequest(format!(
+                        "Invalid chained_resize step: {step} (expected <w>x<h>)"
+                    )))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if steps.len() > config.max_chained_steps {
+            return Err(ImageProviderError::BadRequest(format!(
+                "Too many chained_resize steps: {} (max {})",
+                steps.len(),
+                config.max_chained_steps
+            )));
+        }
+        Ok(steps)
+    }
+
+    fn pngquant(&self) -> Result<Option<u8>> {
+        match self.quantize {
+            None => Ok(None),
+            Some(_) if !cfg!(feature = "pngquant") => Err(ImageProviderError::BadRequest(
+                "quantize is unavailable, compile with --features pngquant".to_string(),
+            )),
+            Some(_) if self.max_colors.is_some() => Err(ImageProviderError::BadRequest(
+                "quantize cannot be combined with max_colors".to_string(),
+            )),
+            Some(quality @ 0..=100) => Ok(Some(quality)),
+            Some(quality) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported quantize: {quality} (expected 0-100)"
+            ))),
+        }
+    }
+
+    fn quantize(&self) -> Result<Option<(usize, Dither)>> {
+        let dither = match self.dither.as_deref() {
+            None | Some("none") => Dither::None,
+            Some("floyd-steinberg") => Dither::FloydSteinberg,
+            Some("ordered") => Dither::Ordered,
+            Some(dither) => {
+                return Err(ImageProviderError::BadRequest(format!(
+                    "Unsupported dither: {dither}"
+                )))
+            }
+        };
+
+        match self.max_colors {
+            Some(colors @ 2..=256) => Ok(Some((colors.into(), dither))),
+            Some(colors) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported max_colors: {colors} (expected 2-256)"
+            ))),
+            None if self.dither.is_some() => Err(ImageProviderError::BadRequest(
+                "dither requires max_colors".to_string(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn fit(&self) -> Result<Fit> {
+        let anchor = match self.anchor.as_deref() {
+            None | Some("center") => (0.5, 0.5),
+            Some("top") => (0.5, 0.0),
+            Some("bottom") => (0.5, 1.0),
+            Some("left") => (0.0, 0.5),
+            Some("right") => (1.0, 0.5),
+            Some("top-left") => (0.0, 0.0),
+            Some("top-right") => (1.0, 0.0),
+            Some("bottom-left") => (0.0, 1.0),
+            Some("bottom-right") => (1.0, 1.0),
+            Some(anchor) => {
+                return Err(ImageProviderError::BadRequest(format!(
+                    "Unsupported anchor: {anchor}"
+                )))
+            }
+        };
+
+        match self.fit.as_deref() {
+            None | Some("cover") => Ok(Fit::Cover(anchor.0, anchor.1)),
+            Some(_) if self.anchor.is_some() => Err(ImageProviderError::BadRequest(
+                "anchor requires fit=cover".to_string(),
+            )),
+            Some("contain") => Ok(Fit::Contain),
+            Some("fill") => Ok(Fit::Fill),
+            Some(fit) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported fit: {fit}"
+            ))),
+        }
+    }
+
+    fn enhance(&self) -> Result<Option<&str>> {
+        match self.enhance.as_deref() {
+            None => Ok(None),
+            Some(_) if !cfg!(feature = "enhance") => Err(ImageProviderError::BadRequest(
+                "enhance is unavailable, compile with --features enhance".to_string(),
+            )),
+            Some(_) if flag(&self.dc_only) => Err(ImageProviderError::BadRequest(
+                "enhance cannot be combined with dc_only".to_string(),
+            )),
+            Some(_) if self.output.as_deref() == Some("thumbnail_strip") => {
+                Err(ImageProviderError::BadRequest(
+                    "enhance cannot be combined with thumbnail_strip".to_string(),
+                ))
+            }
+            Some(mode @ ("auto" | "faces" | "landscape" | "food")) => Ok(Some(mode)),
+            Some(mode) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported enhance: {mode}"
+            ))),
+        }
+    }
+
+    fn blur(&self) -> Result<Option<f32>> {
+        filter_sigma("blur", self.blur.as_deref())
+    }
+
+    fn sharpen(&self) -> Result<Option<f32>> {
+        filter_sigma("sharpen", self.sharpen.as_deref())
+    }
+
+    fn mosaic(&self) -> Result<Option<u32>> {
+        match self.mosaic {
+            Some(0) => Err(ImageProviderError::BadRequest(
+                "mosaic tile size must be above 0".to_string(),
+            )),
+            mosaic => Ok(mosaic),
+        }
+    }
+
+    fn gaussian_pyramid(&self) -> Result<Option<u8>> {
+        match self.gaussian_pyramid {
+            None => Ok(None),
+            Some(levels @ 1..=6) => Ok(Some(levels)),
+            Some(levels) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported gaussian_pyramid: {levels} (expected 1-6)"
+            ))),
+        }
+    }
+
+    /// Index of the requested channel in RGBA
+    fn channel(&self) -> Result<Option<usize>> {
+        match self.channel.as_deref() {
+            None => Ok(None),
+            Some("r") => Ok(Some(0)),
+            Some("g") => Ok(Some(1)),
+            Some("b") => Ok(Some(2)),
+            Some("a") => Ok(Some(3)),
+            Some(channel) => Err(ImageProviderError::BadRequest(format!(
+                "Unsupported channel: {channel} (expected r, g, b or a)"
+            ))),
+        }
+    }
+
+    /// `None` inside for auto, otherwise the color temperature in Kelvin
+    fn white_balance(&self) -> Result<Option<Option<u32>>> {
+        match self.white_balance.as_deref() {
+            None => Ok(None),
+            Some("auto") => Ok(Some(None)),
+            Some(kelvin) => match kelvin.parse() {
+                Ok(kelvin @ 1000..=40000) => Ok(Some(Some(kelvin))),
+                _ => Err(ImageProviderError::BadRequest(format!(
+                    "Unsupported white_balance: {kelvin} (expected auto or 1000-40000)"
+                ))),
+            },
+        }
+    }
+
+    /// Frame count and interval (in ms) for `output=thumbnail_strip`
+    fn thumbnail_strip(&self, config: &ResizeConfig) -> Result<Option<(usize, Option<u32>)>> {
+        if self.output.as_deref() != Some("thumbnail_strip") {
+            return Ok(None);
+        }
+
+        let count = match self.count {
+            Some(count) if count > 0 && count <= config.max_strip_count => count,
+            _ => {
+                return Err(ImageProviderError::BadRequest(format!(
+                    "thumbnail_strip requires a count between 1 and {}",
+                    config.max_strip_count
+                )))
+            }
+        };
+
+        let interval = match self.interval.as_deref().map(str::parse::<f32>) {
+            None => None,
+            Some(Ok(interval)) if interval > 0.0 => Some((interval * 1000.0).round() as u32),
+            Some(_) => {
+                return Err(ImageProviderError::BadRequest(
+                    "interval must be a positive number of seconds".to_string(),
+                ))
+            }
+        };
+        Ok(Some((count, interval)))
+    }
+
+    /// Whether the source is already built at its output size
+    fn keeps_source_size(&self) -> bool {
+        self.gaussian_pyramid.is_some()
+            || self.tile_zoom.is_some()
+            || self.output.as_deref() == Some("thumbnail_strip")
+    }
+
+    fn stitch(&self) -> Result<Option<(Vec<String>, StitchDirection)>> {
+        let Some(stitch) = self.stitch.as_deref() else {
+            return Ok(None);
+        };
+
+        let paths = stitch
+            .split(',')
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect();
+        let direction = match self.stitch_direction.as_deref() {
+            None | Some("h") => StitchDirection::Horizontal,
+            Some("v") => StitchDirection::Vertical,
+            Some(direction) => {
+                return Err(ImageProviderError::BadRequest(format!(
+                    "Unsupported stitch_direction: {direction} (expected h or v)"
+                )))
+            }
+        };
+        Ok(Some((paths, direction)))
+    }
+
+    /// Whether the query asks for more than a plain resize or format change
+    fn transforms(&self) -> bool {
+        self.grid.is_some()
+            || self.crop.is_some()
+            || self.crop_pct.is_some()
+            || flag(&self.gray)
+            || self.flip.is_some()
+            || self.rotate.is_some()
+            || self.stitch.is_some()
+            || self.diff.is_some()
+            || self.watermark.is_some()
+            || self.output_compare.is_some()
+            || self.output_format_chain.is_some()
+            || self.chained_resize.is_some()
+            || self.max_colors.is_some()
+            || self.quantize.is_some()
+            || self.mosaic.is_some()
+            || self.blur.is_some()
+            || self.sharpen.is_some()
+            || flag(&self.dc_only)
+            || self.enhance.is_some()
+            || self.gaussian_pyramid.is_some()
+            || self.channel.is_some()
+            || self.white_balance.is_some()
+            || self.keeps_source_size()
+            || self.quality_metrics()
+    }
+
+    /// The lower of the requested and the configured output size limit
+    fn output_size_limit(&self, config: &ResizeConfig) -> Option<u64> {
+        match (self.output_size_limit, config.output_size_limit) {
+            (Some(requested), Some(global)) => Some(requested.min(global)),
+            (requested, global) => requested.or(global),
+        }
+    }
+
+    fn quality_metrics(&self) -> bool {
+        flag(&self.psnr) || flag(&self.ssim)
+    }
+
+    fn size(&self) -> (Option<u32>, Option<u32>) {
+        (self.w, self.h)
+    }
+
+    /// Device pixel ratio as requested, checked by [`ImageQuery::validate`]
+    fn requested_dpr(&self) -> f32 {
+        self.dpr
+            .as_deref()
+            .and_then(|dpr| dpr.parse().ok())
+            .unwrap_or(1.0)
+    }
+
+    /// Device pixel ratio, capped at `max_dpr` like browsers cap `srcset` densities
+    fn dpr(&self, config: &ResizeConfig) -> f32 {
+        self.requested_dpr().min(config.max_dpr.max(1) as f32)
+    }
+
+    fn encode_options(&self, config: &ResizeConfig) -> Result<EncodeOptions> {
+        let webp_method = self.webp_method.unwrap_or(4);
+        if webp_method > 6 {
+            return Err(ImageProviderError::BadRequest(format!(
+                "Unsupported webp_method: {webp_method} (expected 0-6)"
+            )));
+        }
+
+        let lossless = flag(&self.lossless);
+        let webp_exact = flag(&self.webp_exact);
+        if webp_exact && !lossless {
+            return Err(ImageProviderError::BadRequest(
+                "webp_exact requires lossless=1".to_string(),
+            ));
+        }
+
+        let quality = self.q;
+        if quality.is_some() && lossless {
+            return Err(ImageProviderError::BadRequest(
+                "q cannot be combined with lossless=1".to_string(),
+            ));
+        }
+
+        let webp_alpha_quality = self.webp_alpha_quality;
+        if webp_alpha_quality.is_some_and(|quality| quality > 100) {
+            return Err(ImageProviderError::BadRequest(
+                "webp_alpha_quality must be between 0 and 100".to_string(),
+            ));
+        }
+        if webp_alpha_quality.is_some() && lossless {
+            return Err(ImageProviderError::BadRequest(
+                "webp_alpha_quality requires lossy encoding".to_string(),
+            ));
+        }
+
+        let webp_segments = self.webp_segments.unwrap_or(4);
+        if !(1..=4).contains(&webp_segments) {
+            return Err(ImageProviderError::BadRequest(format!(
+                "Unsupported webp_segments: {webp_segments} (expected 1-4)"
+            )));
+        }
+
+        let avif_speed = self.avif_speed.unwrap_or(config.avif_speed);
+        if !(1..=10).contains(&avif_speed) {
+            return Err(ImageProviderError::BadRequest(format!(
+                "Unsupported avif_speed: {avif_speed} (expected 1-10)"
+            )));
+        }
+        if let Some(codec) = &self.avif_codec {
+            if !AVIF_CODECS.contains(&codec.as_str()) {
+                return Err(ImageProviderError::BadRequest(format!(
+                    "Unsupported avif_codec: {codec} (available: {})",
+                    AVIF_CODECS.join(", ")
+                )));
+            }
+        }
+
+        let jpeg_huffman_optimize = flag(&self.jpeg_huffman_optimize);
+        if jpeg_huffman_optimize && !cfg!(feature = "mozjpeg") {
+            return Err(ImageProviderError::BadRequest(
+                "jpeg_huffman_optimize is unavailable, compile with --features mozjpeg".to_string(),
+            ));
+        }
+
+        let png_compression = match self.compress.as_deref() {
+            Some(compress) => compress.parse::<PngCompression>().map_err(|_| {
+                ImageProviderError::BadRequest(format!(
+                    "Unsupported compress: {compress} (expected default, fast or best)"
+                ))
+            })?,
+            None => config.png_compression,
+        };
+
+        let j2k_ratio = self.j2k_quality;
+        if j2k_ratio == Some(0) {
+            return Err(ImageProviderError::BadRequest(
+                "j2k_quality must be a compression ratio above 0".to_string(),
+            ));
+        }
+
+        Ok(EncodeOptions {
+            webp_method,
+            webp_exact,
+            quality,
+            webp_alpha_quality,
+            webp_segments,
+            avif_speed,
+            avif_tiles: self.avif_tiles()?,
+            avif_quality: config.avif_quality,
+            jpeg_quality: config.jpeg_quality,
+            webp_quality: config.webp_quality.filter(|_| !lossless),
+            png_compression,
+            png_filter: config.png_filter,
+            jpeg_huffman_optimize,
+            j2k_ratio,
+            indexed_png: self.quantize.is_some(),
+            background: self.background()?,
+        })
+    }
+
+    fn background(&self) -> Result<[u8; 3]> {
+        let Some(bg) = self.bg.as_deref() else {
+            return Ok([255, 255, 255]);
+        };
+        parse_color(bg).ok_or(ImageProviderError::BadRequest(format!(
+            "Invalid bg: {bg} (expected a hex color or a color name)"
+        )))
+    }
+
+    fn ce(&self) -> bool {
+        flag(&self.ce)
+    }
+
+    fn cache_control(&self, config: &ResizeConfig) -> String {
+        const MAX_AGE: u64 = 365 * 24 * 60 * 60; // 1 year in seconds
+        if !config.allow_cache_control_override {
+            return format!("public, max-age={MAX_AGE}");
+        }
+
+        let mut cache_control = format!("public, max-age={}", self.max_age.unwrap_or(MAX_AGE));
+        if let Some(s_maxage) = self.s_maxage {
+            cache_control.push_str(&format!(", s-maxage={s_maxage}"));
+        }
+        cache_control
+    }
+}
+
+fn flag(value: &Option<String>) -> bool {
+    match value.as_deref() {
+        Some("0") | Some("false") | Some("no") => false,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Ready once every image root can be read
+async fn provide_readiness(
+    State(ImageState { root, mounts, .. }): State<ImageState>,
+) -> Result<&'static str> {
+    let roots: Vec<&PathBuf> = match mounts.is_empty() {
+        true => vec![&root],
+        false => mounts.values().collect(),
+    };
+    for root in roots {
+        if tokio::fs::read_dir(root).await.is_err() {
+            return Err(ImageProviderError::Unavailable(format!(
+                "Image root {root:?} is not readable"
+            )));
+        }
+    }
+    Ok("ready")
+}
+
+/// Counters of the processed image cache, served at `/__cache/stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub capacity: usize,
+    pub size: usize,
+}
+
+async fn provide_cache_stats(
+    State(ImageState { cache, .. }): State<ImageState>,
+) -> Json<CacheStats> {
+    let cache = cache.lock().await;
+    Json(CacheStats {
+        hits: cache.cache_hits().unwrap_or(0),
+        misses: cache.cache_misses().unwrap_or(0),
+        capacity: cache.cache_capacity().unwrap_or(0),
+        size: cache.cache_size(),
+    })
+}
+
+/// Request counters in the Prometheus text format, served at `/metrics`
+async fn provide_metrics(State(ImageState { metrics, .. }): State<ImageState>) -> Response {
+    let body = metrics.render();
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct ClearCache {
+    /// Source path (relative to root, or `{mount_name}/{path}` with several roots) to evict,
+    /// everything when omitted
+    path: Option<String>,
+}
+
+/// Drop cached images, e.g. after sources changed on disk
+async fn clear_cache(
+    State(ImageState {
+        root,
+        mounts,
+        config,
+        cache,
+        ..
+    }): State<ImageState>,
+    body: Option<Json<ClearCache>>,
+) -> Result<StatusCode> {
+    let mut cache = cache.lock().await;
+    match body.and_then(|Json(body)| body.path) {
+        Some(path) => {
+            let (root, path) = match mounts.is_empty() {
+                true => (&root, path.as_str()),
+                false => {
+                    let (mount, path) = path.split_once('/').unwrap_or((&path, ""));
+                    (mounts.get(mount).ok_or(ImageProviderError::NotFound)?, path)
+                }
+            };
+            // Cache keys hold canonical paths, the source may already be gone though
+            let path = sanitize_path(root, path)?;
+            let stale: Vec<CacheKey> = cache
+                .key_order()
+                .filter(|(key_path, ..)| *key_path == path)
+                .cloned()
+                .collect();
+            // Disk entries are named by a hash, only those still in memory can be found
+            for key in stale {
+                if let Some(cache_dir) = &config.cache_dir {
+                    let cache_path = disk_cache_path(cache_dir, &key);
+                    let _ = tokio::fs::remove_file(cache_path.with_extension("br")).await;
+                    let _ = tokio::fs::remove_file(cache_path).await;
+                }
+                cache.cache_remove(&key);
+            }
+        }
+        None => {
+            if let Some(cache_dir) = &config.cache_dir {
+                clear_disk_cache(cache_dir).await?;
+            }
+            cache.cache_clear();
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct IndexQuery {
+    /// Directory (relative to root) to list, the root itself when omitted
+    dir: Option<String>,
+    /// Also list the images in subdirectories
+    recursive: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IndexEntry {
+    /// Relative to root, usable as the image path
+    path: String,
+    format: String,
+}
+
+/// Images in a directory, by path. Symlinks are skipped
+async fn provide_index(
+    State(ImageState { root, .. }): State<ImageState>,
+    Query(query): Query<IndexQuery>,
+) -> Result<Json<Vec<IndexEntry>>> {
+    let root = root
+        .canonicalize()
+        .map_err(|_| ImageProviderError::NotFound)?;
+    let dir = match query.dir.as_deref() {
+        None | Some("") => root.clone(),
+        Some(dir) => sanitize_path(&root, dir)?,
+    };
+    if !dir.exists() {
+        return Err(ImageProviderError::NotFound);
+    }
+    if !dir.is_dir() {
+        return Err(ImageProviderError::BadRequest(
+            "dir is not a directory".to_string(),
+        ));
+    }
+
+    let recursive = flag(&query.recursive);
+    tokio::task::spawn_blocking(move || {
+        let mut entries = Vec::new();
+        list_images(&root, &dir, recursive, &mut entries)?;
+        entries.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        Ok(Json(entries))
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to list images: {e}")))?
+}
+
+fn list_images(
+    root: &std::path::Path,
+    dir: &std::path::Path,
+    recursive: bool,
+    entries: &mut Vec<IndexEntry>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let (path, file_type) = (entry.path(), entry.file_type()?);
+        if file_type.is_dir() && recursive {
+            list_images(root, &path, recursive, entries)?;
+        } else if file_type.is_file() {
+            let Some(format) = find_image_mime(MimeGuess::from_path(&path)) else {
+                continue;
+            };
+            // Paths in URLs use `/` on every platform
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let relative: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+            entries.push(IndexEntry {
+                path: relative.join("/"),
+                format: format!("{format:?}").to_lowercase(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ImageInfo {
+    width: u32,
+    height: u32,
+    format: &'static str,
+    file_size_bytes: u64,
+}
+
+/// Dimensions, format and size of an image, read from its header only.
+/// Tagged by the file's mtime, so unchanged files are answered with `304 Not Modified`
+async fn provide_info(
+    State(ImageState { root, config, .. }): State<ImageState>,
+    Path(path): Path<String>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response> {
+    let (path, mime) = get_path_and_mime(root, &path)?;
+    let metadata = tokio::fs::metadata(&path).await?;
+    let mut headers = HeaderMap::new();
+    let cache_control = format!("public, max-age={}", config.info_max_age);
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&cache_control).unwrap(),
+    );
+    let etag = hash((&path, metadata.modified().ok()));
+    if let Some(response) = check_etag(&mut headers, etag, &if_none_match) {
+        return Ok(response);
+    }
+
+    let info = tokio::task::spawn_blocking(move || -> Result<_> {
+        let file_size_bytes = metadata.len();
+        if is_svg(&path) {
+            let (width, height) = svg_size(&std::fs::read(&path)?)?;
+            return Ok(Json(ImageInfo {
+                width,
+                height,
+                format: "svg",
+                file_size_bytes,
+            }));
+        }
+
+        let reader = ImageReader::open(&path).and_then(ImageReader::with_guessed_format)?;
+        let format = reader.format().unwrap_or(mime);
+        let (width, height) = reader
+            .into_dimensions()
+            .map_err(ImageProviderError::DecodeError)?;
+
+        Ok(Json(ImageInfo {
+            width,
+            height,
+            format: format.extensions_str().first().copied().unwrap_or_default(),
+            file_size_bytes,
+        }))
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to read image: {e}")))??;
+    Ok((headers, info).into_response())
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    /// `json` answers with [`UploadInfo`] instead of the converted image
+    response: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UploadInfo {
+    detected_format: String,
+    output_format: String,
+    width: u32,
+    height: u32,
+    /// Size of the converted image
+    bytes: usize,
+}
+
+/// Largest upload when `max_source_file_bytes` is unset
+const UPLOAD_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Convert the first file of a `multipart/form-data` upload to `upload_output`. The format is
+/// detected from the content, the file name and content type are ignored
+async fn provide_upload(
+    State(ImageState { config, .. }): State<ImageState>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Result<Response> {
+    let json = match query.response.as_deref() {
+        None | Some("image") => false,
+        Some("json") => true,
+        Some(response) => {
+            return Err(ImageProviderError::BadRequest(format!(
+                "Unsupported response: {response} (expected image or json)"
+            )))
+        }
+    };
+    let bad_upload = |e: MultipartError| ImageProviderError::BadRequest(e.body_text());
+    let mut field = loop {
+        match multipart.next_field().await.map_err(bad_upload)? {
+            Some(field) if field.file_name().is_some() => break field,
+            Some(_) => continue,
+            None => {
+                return Err(ImageProviderError::BadRequest(
+                    "The upload has no file".to_string(),
+                ))
+            }
+        }
+    };
+
+    // Collected chunk by chunk, so an oversized upload is refused before it is all received
+    let limit = config.max_source_file_bytes.unwrap_or(UPLOAD_SIZE_LIMIT);
+    let mut data = vec![];
+    while let Some(chunk) = field.chunk().await.map_err(bad_upload)? {
+        data.extend_from_slice(&chunk);
+        let bytes = data.len() as u64;
+        if bytes > limit {
+            return Err(ImageProviderError::SourceTooLarge { bytes, limit });
+        }
+    }
+    let (detected, output, image, bytes) =
+        tokio::task::spawn_blocking(move || convert_upload(&data, &config))
+            .await
+            .map_err(|e| {
+                ImageProviderError::Internal(format!("Failed to convert upload: {e}"))
+            })??;
+
+    if json {
+        let format_name = |format: ImageFormat| format!("{format:?}").to_lowercase();
+        return Ok(Json(UploadInfo {
+            detected_format: format_name(detected),
+            output_format: format_name(output),
+            width: image.width(),
+            height: image.height(),
+            bytes: bytes.len(),
+        })
+        .into_response());
+    }
+    let headers = get_response_headers(&OutputFormat::Image(output), "no-store")?;
+    Ok((headers, bytes).into_response())
+}
+
+/// Detect the format of an upload, check it is allowed and convert it to `upload_output`.
+/// Returns the detected and output formats, the decoded image and the converted bytes
+fn convert_upload(
+    data: &[u8],
+    config: &ResizeConfig,
+) -> Result<(ImageFormat, ImageFormat, DynamicImage, Bytes)> {
+    let detected = image::guess_format(data).map_err(|_| {
+        ImageProviderError::UnsupportedFormat("the uploaded file is not a known image".to_string())
+    })?;
+    let allowed = &config.upload_allowed_extensions;
+    let is_allowed = |allowed: &String| {
+        let extensions = detected.extensions_str();
+        extensions
+            .iter()
+            .any(|ext| allowed.eq_ignore_ascii_case(ext))
+    };
+    if !allowed.is_empty() && !allowed.iter().any(is_allowed) {
+        return Err(ImageProviderError::UnsupportedMediaType(format!(
+            "Uploads of {} are not allowed",
+            detected.to_mime_type()
+        )));
+    }
+    let output = match &config.upload_output {
+        // Checked when the config was built
+        Some(output) => ImageFormat::from_extension(output).unwrap(),
+        None => detected,
+    };
+    let options = ImageQuery::default().encode_options(config)?;
+
+    let image = image::load_from_memory_with_format(data, detected)
+        .map_err(ImageProviderError::DecodeError)?;
+    let bytes = encode_with_options(output, &image, &options)?;
+    Ok((detected, output, image, bytes))
+}
+
+/// Serve `favicon.ico` from the root, or generate one from `favicon.png`, `favicon.jpg` or `logo.png`
+async fn provide_favicon(
+    State(ImageState {
+        root,
+        config,
+        favicon,
+        ..
+    }): State<ImageState>,
+) -> Result<Response> {
+    let headers = get_response_headers(
+        &OutputFormat::Image(ImageFormat::Ico),
+        "public, max-age=31536000",
+    )?;
+
+    if let Ok(bytes) = tokio::fs::read(root.join("favicon.ico")).await {
+        return Ok((headers, bytes).into_response());
+    }
+
+    let bytes = favicon
+        .get_or_try_init(|| generate_favicon(&root, &config))
+        .await?
+        .clone();
+    Ok((headers, bytes).into_response())
+}
+
+async fn generate_favicon(root: &std::path::Path, config: &ResizeConfig) -> Result<Bytes> {
+    const SOURCES: [&str; 3] = ["favicon.png", "favicon.jpg", "logo.png"];
+    const SIZES: [u32; 3] = [16, 32, 48];
+
+    let Some(path) = SOURCES
+        .iter()
+        .map(|name| root.join(name))
+        .find(|path| path.is_file())
+    else {
+        return Err(ImageProviderError::NotFound);
+    };
+    debug!("Generating favicon from: {path:?}");
+
+    let src_image = load_image(load_file(&path, config).await?).await?;
+    let src_image = DynamicImage::ImageRgba8(src_image.to_rgba8());
+    let encoding_error = ImageProviderError::EncodeError;
+
+    let mut frames = Vec::with_capacity(SIZES.len());
+    for size in SIZES {
+        let mut frame = DynamicImage::new(size, size, src_image.color());
+        resize_image(config, &src_image, &mut frame)?;
+        frames.push(
+            IcoFrame::as_png(frame.as_bytes(), size, size, frame.color().into())
+                .map_err(encoding_error)?,
+        );
+    }
+
+    let mut bytes = vec![];
+    IcoEncoder::new(&mut bytes)
+        .encode_images(&frames)
+        .map_err(encoding_error)?;
+    Ok(Bytes::from(bytes))
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            path = %path,
+            dst_mime = tracing::field::Empty,
+            dst_width = tracing::field::Empty,
+            dst_height = tracing::field::Empty,
+            dpr = tracing::field::Empty,
+            cache_hit = tracing::field::Empty,
+        )
+    )
+)]
+async fn provide_images(
+    State(state): State<ImageState>,
+    Query(query): Query<ImageQuery>,
+    Path(path): Path<String>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    request_headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Response> {
+    let events = state.events.clone();
+    let metrics = state.metrics.clone();
+    let range = request_headers.typed_get::<Range>().map(TypedHeader);
+    let if_none_match = request_headers.typed_get::<IfNoneMatch>().map(TypedHeader);
+    let checked = check_signature(&state.config, &uri, &query)
+        .and_then(|()| query.validate().map_err(Into::into));
+    let result = match checked {
+        Ok(()) => {
+            let result = serve_image(
+                state.clone(),
+                query.clone(),
+                &path,
+                range,
+                if_none_match.clone(),
+                connect_info,
+                &request_headers,
+            )
+            .await;
+            // Only a missing source gets the placeholder, not a missing stitch, diff or watermark
+            match (result, state.config.not_found_image.clone()) {
+                (Err(ImageProviderError::NotFound), Some(image))
+                    if matches!(
+                        get_path_and_mime(state.root.clone(), &path),
+                        Err(ImageProviderError::NotFound)
+                    ) =>
+                {
+                    serve_not_found_image(
+                        state,
+                        &image,
+                        query,
+                        if_none_match,
+                        connect_info,
+                        &request_headers,
+                    )
+                    .await
+                }
+                (result, _) => result,
+            }
+        }
+        Err(e) => Err(e),
+    };
+    if let (Some(events), Err(error)) = (events, &result) {
+        events.on_error(path.as_ref(), error);
+    }
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(error) => error.status_code(),
+    };
+    metrics.record_request(status.as_u16());
+    result
+}
+
+/// `not_found_image` processed like the missing image would have been, still with a 404 status.
+/// Without the file, this is the usual `NotFound`
+async fn serve_not_found_image(
+    state: ImageState,
+    image: &std::path::Path,
+    query: ImageQuery,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    request_headers: &HeaderMap,
+) -> Result<Response> {
+    let root = image
+        .parent()
+        .filter(|root| !root.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    let Some(name) = image.file_name().and_then(|name| name.to_str()) else {
+        return Err(ImageProviderError::NotFound);
+    };
+    trace!("Serving not found image: {image:?}");
+    let state = ImageState {
+        root: root.to_path_buf(),
+        ..state
+    };
+    let mut response = serve_image(
+        state,
+        query,
+        name,
+        None,
+        if_none_match,
+        connect_info,
+        request_headers,
+    )
+    .await?;
+    if response.status().is_success() {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    Ok(response)
+}
+
+/// [`provide_images`] with the root picked by the first path segment
+async fn provide_mounted_images(
+    State(state): State<ImageState>,
+    query: Query<ImageQuery>,
+    Path((mount, path)): Path<(String, String)>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    request_headers: HeaderMap,
+    uri: OriginalUri,
+) -> Result<Response> {
+    let Some(root) = state.mounts.get(&mount).cloned() else {
+        return Err(ImageProviderError::NotFound);
+    };
+    provide_images(
+        State(ImageState { root, ..state }),
+        query,
+        Path(path),
+        connect_info,
+        request_headers,
+        uri,
+    )
+    .await
+}
+
+/// With a `secret` configured, only requests signed with it are processed. The signature covers
+/// every other parameter, so none can be added or changed. `signed_operations` also refuses
+/// unverifiable requests, and a second `s` or `sig` that the signature does not cover
+fn check_signature(
+    config: &ResizeConfig,
+    uri: &axum::http::Uri,
+    image_query: &ImageQuery,
+) -> Result<()> {
+    let signed_operations = flag(&image_query.signed_operations);
+    let Some(secret) = &config.secret else {
+        return match signed_operations {
+            true => Err(ImageProviderError::Forbidden(
+                "signed_operations requires a secret to be configured".to_string(),
+            )),
+            false => Ok(()),
+        };
+    };
+
+    let query = uri.query().unwrap_or_default();
+    let sig = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("s=").or(param.strip_prefix("sig=")))
+        .ok_or(ImageProviderError::Forbidden(
+            "Missing s parameter".to_string(),
+        ))?;
+    let sigs = query
+        .split('&')
+        .filter(|param| signature::is_signature(param))
+        .count();
+    if signed_operations && sigs > 1 {
+        return Err(ImageProviderError::Forbidden(
+            "signed_operations allows a single s parameter".to_string(),
+        ));
+    }
+    if !signature::verify(secret, uri.path(), query, sig) {
+        return Err(ImageProviderError::Forbidden("Invalid s".to_string()));
+    }
+    Ok(())
+}
+
+async fn serve_image(
+    ImageState {
+        root,
+        config,
+        cache,
+        events,
+        in_flight,
+        #[cfg(feature = "enhance")]
+        enhancer,
+        metrics,
+        ..
+    }: ImageState,
+    mut query: ImageQuery,
+    path: &str,
+    range: Option<TypedHeader<Range>>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    request_headers: &HeaderMap,
+) -> Result<Response> {
+    if flag(&query.thumb) {
+        let mut preset = config.thumbnail_preset();
+        // An explicit lossless=1 wins over the preset quality like any other parameter
+        if flag(&query.lossless) && query.q.is_none() {
+            preset.q = None;
+        }
+        query = query.or(preset);
+        query.validate()?;
+    }
+
+    let (path, raw_mime) = get_path_and_mime(root.clone(), path)?;
+
+    // Without an explicit format, encode to the best one the client accepts. Written into the
+    // query so the negotiated format is part of the cache key and ETag. Only images that are
+    // processed anyway are negotiated, an untouched original is served as is
+    let processed = query.size() != (None, None)
+        || query.dpr(&config) - 1.0 > f32::EPSILON
+        || query.transforms();
+    let negotiable = processed
+        && query.output.is_none()
+        && !flag(&query.lqip)
+        && !flag(&query.dc_only)
+        && query.output_compare.is_none()
+        && query.output_format_chain.is_none()
+        && !matches!(raw_mime, ImageFormat::Gif | ImageFormat::Ico);
+    if negotiable {
+        let accept = request_headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok());
+        // A lossless source only becomes lossy when a quality is asked for, so it is kept as PNG
+        // or lossless WebP rather than the lossy AVIF or JPEG defaults
+        let lossy_source = matches!(raw_mime, ImageFormat::Jpeg | ImageFormat::Avif);
+        let candidates: &[ImageFormat] = match lossy_source || query.q.is_some() {
+            true => &[
+                ImageFormat::Avif,
+                ImageFormat::WebP,
+                ImageFormat::Png,
+                ImageFormat::Jpeg,
+            ],
+            false => &[ImageFormat::WebP, ImageFormat::Png],
+        };
+        if let Some(format) = accept.and_then(|accept| negotiate_format(accept, candidates)) {
+            query.output = Some(format.extensions_str()[0].to_string());
+            // Lossless WebP of a photo is far larger than the JPEG it came from
+            if format == ImageFormat::WebP
+                && lossy_source
+                && query.q.is_none()
+                && !flag(&query.lossless)
+            {
+                query.q = Some(config.webp_quality.unwrap_or(config.jpeg_quality));
+            }
+        }
+    }
+    if query.output_compare.is_some() && query.output_format_chain.is_some() {
+        return Err(ImageProviderError::BadRequest(
+            "output_compare cannot be combined with output_format_chain".to_string(),
+        ));
+    }
+    let formats = query.output_compare()?.or(query.output_format_chain()?);
+    let dst_mime = match formats.as_deref() {
+        Some([(_, first), ..]) => OutputFormat::Image(*first),
+        _ => query.output()?.unwrap_or(OutputFormat::Image(raw_mime)),
+    };
+    let (dst_width, dst_height) = query.size();
+    let dpr = query.dpr(&config);
+    if query.requested_dpr() > dpr {
+        debug!("Clamping dpr {} to max_dpr {dpr}", query.requested_dpr());
+    }
+    let ce = query.ce();
+    let encode_options = query.encode_options(&config)?;
+    query.quantize()?;
+    query.pngquant()?;
+    query.fit()?;
+    query.mosaic()?;
+    query.blur()?;
+    query.sharpen()?;
+    let enhance = query.enhance()?;
+    #[cfg(feature = "enhance")]
+    let enhance = match (enhance, enhancer) {
+        (Some(mode), Some(enhancer)) => Some((mode, enhancer)),
+        (Some(_), None) => {
+            return Err(ImageProviderError::BadRequest(
+                "enhance requires enhance_service_url to be configured".to_string(),
+            ))
+        }
+        (None, _) => None,
+    };
+    let pyramid = query.gaussian_pyramid()?;
+    let channel = query.channel()?;
+    let white_balance = query.white_balance()?;
+    let strip = query.thumbnail_strip(&config)?;
+    let tile = query.tile()?;
+    let crop = query.crop()?;
+    let crop_pct = query.crop_pct()?;
+    let gray = flag(&query.gray);
+    let (flip_h, flip_v) = query.flip()?;
+    let rotate = query.rotate()?;
+
+    let grid = query.grid()?;
+    if grid.is_some() && !config.allow_grid_split {
+        return Err(ImageProviderError::BadRequest(
+            "Grid split is disabled".to_string(),
+        ));
+    }
+    if grid.is_some() && formats.is_some() {
+        return Err(ImageProviderError::BadRequest(
+            "Grid split cannot be combined with output_compare or output_format_chain".to_string(),
+        ));
+    }
+
+    let stitch = query.stitch()?;
+    if let Some((paths, _)) = &stitch {
+        if paths.len() > config.max_stitch_images {
+            return Err(ImageProviderError::BadRequest(format!(
+                "Too many images to stitch: {} (max {})",
+                paths.len(),
+                config.max_stitch_images
+            )));
+        }
+    }
+
+    if tile.is_some()
+        && (grid.is_some() || stitch.is_some() || pyramid.is_some() || strip.is_some())
+    {
+        return Err(ImageProviderError::BadRequest(
+            "Map tiles cannot be combined with grid, stitch, gaussian_pyramid or thumbnail_strip"
+                .to_string(),
+        ));
+    }
+
+    if flag(&query.psnr) && !config.allow_psnr {
+        return Err(ImageProviderError::BadRequest(
+            "PSNR is disabled".to_string(),
+        ));
+    }
+    if flag(&query.ssim) && !config.allow_ssim {
+        return Err(ImageProviderError::BadRequest(
+            "SSIM is disabled".to_string(),
+        ));
+    }
+    let image_output = matches!(dst_mime, OutputFormat::Image(_) | OutputFormat::Auto);
+    if query.quality_metrics() && (!image_output || grid.is_some()) {
+        return Err(ImageProviderError::BadRequest(
+            "Quality metrics require an image output".to_string(),
+        ));
+    }
+
+    if matches!(dst_mime, OutputFormat::Raw(_)) && !config.allow_raw_output {
+        return Err(ImageProviderError::BadRequest(
+            "Raw output is disabled".to_string(),
+        ));
+    }
+
+    debug!(
+        "Processing image: {path:?} to mime: {dst_mime:?}, size: {:?}x{:?}, dpr: {dpr}, ce: {ce}",
+        dst_width.unwrap_or(0),
+        dst_height.unwrap_or(0)
+    );
+    record_span!(
+        "dst_mime" = dst_mime,
+        "dst_width" = dst_width,
+        "dst_height" = dst_height,
+        "dpr" = dpr,
+    );
+
+    let range = range.map(|TypedHeader(range)| range);
+    let mut headers = get_response_headers(&dst_mime, &query.cache_control(&config))?;
+    if negotiable {
+        headers.insert(VARY, HeaderValue::from_static("accept"));
+    }
+
+    // If no resizing is needed, serve the original file directly
+    let svg = is_svg(&path);
+    let eq_raw = dst_width.is_none()
+        && dst_height.is_none()
+        && f32::EPSILON > (dpr - 1.0)
+        && OutputFormat::Image(raw_mime) == dst_mime
+        && !query.transforms()
+        && !svg;
+    // Icons are not decoded, GIFs only when transformed, keeping their animation otherwise
+    let exclude = raw_mime == ImageFormat::Ico && strip.is_none();
+
+    let dc_only = flag(&query.dc_only);
+    if dc_only && !cfg!(feature = "mozjpeg") {
+        return Err(ImageProviderError::BadRequest(
+            "dc_only is unavailable, compile with --features mozjpeg".to_string(),
+        ));
+    }
+    let jpeg = OutputFormat::Image(ImageFormat::Jpeg);
+    if dc_only && (raw_mime != ImageFormat::Jpeg || dst_mime != jpeg || strip.is_some()) {
+        return Err(ImageProviderError::BadRequest(
+            "dc_only requires a JPEG source and JPEG output".to_string(),
+        ));
+    }
+    if eq_raw || exclude {
+        trace!("Serving original image: {path:?}");
+        if dst_mime == OutputFormat::Auto {
+            let mime = HeaderValue::from_static(raw_mime.to_mime_type());
+            headers.insert(CONTENT_TYPE, mime);
+        }
+        let file = load_file(&path, &config).await?;
+        let modified = file.metadata().await.and_then(|m| m.modified()).ok();
+        let etag = hash(modified) ^ hash(&query);
+        if let Some(response) = check_etag(&mut headers, etag, &if_none_match) {
+            return Ok(response);
+        }
+        let body = KnownSize::file(file).await.unwrap();
+        check_range(&range, body.byte_size(), &config)?;
+        let ranged = Ranged::new(range, body);
+        return Ok((headers, ranged).into_response());
+    }
+
+    let client_ip = connect_info
+        .filter(|_| config.per_ip_cache)
+        .map(|Extension(ConnectInfo(addr))| addr.ip());
+    let key = (path.clone(), query.clone(), client_ip);
+
+    if config.precompress {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    // Ranges are of the identity encoding, so only whole responses are served precompressed
+    if let (true, Some(cache_dir), None) = (
+        config.precompress && accepts_brotli(request_headers),
+        &config.cache_dir,
+        &range,
+    ) {
+        let cache_path = disk_cache_path(cache_dir, &key).with_extension("br");
+        if let Some(compressed) = load_disk_cache(&cache_path, &path).await {
+            trace!("Serving precompressed image: {path:?}");
+            if let Some(events) = &events {
+                events.on_cache_hit(&path, &query);
+            }
+            metrics.record_cache_hit();
+            record_span!("cache_hit" = true);
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+            return serve_processed(headers, compressed, None, &if_none_match, &config).await;
+        }
+    }
+
+    let cached = cache.lock().await.cache_get(&key).cloned();
+    if let Some(cached) = cached {
+        trace!(
+            "Serving cached image: {path:?} (mime: {dst_mime:?}, size {:?}x{:?}, dpr: {dpr})",
+            dst_width.unwrap_or(0),
+            dst_height.unwrap_or(0)
+        );
+        if let Some(events) = &events {
+            events.on_cache_hit(&path, &query);
+        }
+        metrics.record_cache_hit();
+        record_span!("cache_hit" = true);
+        return serve_processed(headers, cached, range, &if_none_match, &config).await;
+    }
+
+    // Wait for an identical request that is already processing, instead of repeating its work
+    let waiting = match in_flight.entry(key.clone()) {
+        Entry::Occupied(entry) => Some(entry.get().subscribe()),
+        Entry::Vacant(entry) => {
+            entry.insert(broadcast::channel(1).0);
+            None
+        }
+    };
+    let flight = match waiting {
+        Some(mut receiver) => {
+            // A closed channel means the other request failed or was cancelled, so process it
+            // here to get its own error
+            if let Ok(processed) = receiver.recv().await {
+                trace!("Serving coalesced image: {path:?}");
+                return serve_processed(headers, processed, range, &if_none_match, &config).await;
+            }
+            None
+        }
+        None => Some(InFlightGuard {
+            in_flight: &in_flight,
+            key: key.clone(),
+        }),
+    };
+
+    if let Some(events) = &events {
+        events.on_cache_miss(&path, &query);
+    }
+    metrics.record_cache_miss();
+    record_span!("cache_hit" = false);
+
+    if config.lossless_jpeg_pass_through && raw_mime == ImageFormat::Jpeg && dst_mime == jpeg {
+        warn!(
+            "Re-encoding JPEG {path:?}, lossless JPEG pass-through is not supported yet so \
+             quality degrades with every generation"
+        );
+    }
+
+    let processed: Result<ProcessedImage> = async {
+        // Only the first frame would be decoded, strips read every frame on their own
+        if raw_mime == ImageFormat::WebP && strip.is_none() && is_animated_webp(&path)? {
+            return Err(ImageProviderError::UnsupportedMediaType(
+                "Resizing animated WebP is not supported yet, request it without parameters"
+                    .to_string(),
+            ));
+        }
+
+        if let Some(cache_dir) = &config.cache_dir {
+            if let Some(processed) = load_disk_cache(&disk_cache_path(cache_dir, &key), &path).await
+            {
+                trace!("Serving disk cached image: {path:?}");
+                return Ok(processed);
+            }
+        }
+
+        let file = load_file(&path, &config).await?;
+        // Only plain resizes and conversions can fall back to the source, as it shows the same
+        let fallback = config.avoid_enlargement
+            && !svg
+            && strip.is_none()
+            && !query.transforms()
+            && matches!(dst_mime, OutputFormat::Image(_) | OutputFormat::Auto);
+        // The source bytes are kept when they may be served instead of the output
+        let mut source = None;
+        let (frames, src_image) = match strip {
+            Some(_) => (Some(load_frames(file, raw_mime).await?), None),
+            None if dc_only => (None, Some(load_jpeg_dc(file).await?)),
+            None if svg => {
+                let size = (dst_width, dst_height);
+                (None, Some(load_svg(file, size, dpr, &config).await?))
+            }
+            None => match enhance {
+                #[cfg(feature = "enhance")]
+                Some((mode, enhancer)) => {
+                    let source = load_enhanced(&enhancer, &path, raw_mime, mode).await?;
+                    (None, Some(source))
+                }
+                _ if fallback => {
+                    let (bytes, image) = load_image_buffered(file).await?;
+                    source = Some(bytes);
+                    (None, Some(image))
+                }
+                _ => (None, Some(load_image(file).await?)),
+            },
+        };
+
+        let mut stitched = vec![];
+        if let Some((paths, _)) = &stitch {
+            for path in paths {
+                let (path, _) = get_path_and_mime(root.clone(), path)?;
+                stitched.push(load_source(&path, &config).await?);
+            }
+        }
+
+        let other = match &query.diff {
+            Some(diff) => {
+                let (path, _) = get_path_and_mime(root.clone(), diff)?;
+                Some(load_source(&path, &config).await?)
+            }
+            None => None,
+        };
+
+        let watermark = match &query.watermark {
+            Some(watermark) => {
+                let (path, format) = get_path_and_mime(root.clone(), watermark)?;
+                if !matches!(format, ImageFormat::Png | ImageFormat::WebP) {
+                    return Err(ImageProviderError::BadRequest(
+                        "watermark must be a PNG or WebP image".to_string(),
+                    ));
+                }
+                Some(load_source(&path, &config).await?)
+            }
+            None => None,
+        };
+
+        // Resizing and encoding are CPU bound, keep them off the async executor
+        let started = Instant::now();
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let (bytes, extra_headers) = tokio::task::spawn_blocking({
+            let (config, query) = (config.clone(), query.clone());
+            move || -> Result<(Bytes, HeaderMap)> {
+                // Blocking threads don't inherit the request span
+                #[cfg(feature = "tracing")]
+                let _span = span.enter();
+                let mut src_image = match (frames, src_image) {
+                    (Some(frames), _) => {
+                        let (count, interval) = strip.unwrap();
+                        thumbnail_strip(&config, &query, &frames, count, interval)?
+                    }
+                    (None, src_image) => src_image.unwrap(),
+                };
+
+                if let Some(crop) = crop {
+                    src_image = crop_image(&src_image, crop)?;
+                }
+                if let Some(margins) = crop_pct {
+                    src_image = crop_margins(&src_image, margins)?;
+                }
+                if gray {
+                    src_image = src_image.grayscale();
+                }
+                if flip_h {
+                    src_image = src_image.fliph();
+                }
+                if flip_v {
+                    src_image = src_image.flipv();
+                }
+                // Turning by 90 or 270 degrees swaps the size used for the output
+                src_image = match rotate {
+                    Some(90) => src_image.rotate90(),
+                    Some(180) => src_image.rotate180(),
+                    Some(270) => src_image.rotate270(),
+                    _ => src_image,
+                };
+
+                if let Some((_, direction)) = stitch {
+                    let images = std::iter::once(src_image).chain(stitched).collect();
+                    src_image = stitch_images(&config, images, direction)?;
+                }
+
+                if let Some(other) = other {
+                    src_image = diff_images(&config, &src_image, &other)?;
+                }
+
+                if let Some(levels) = pyramid {
+                    src_image = gaussian_pyramid(src_image, levels);
+                }
+
+                if let Some(tile) = tile {
+                    src_image = map_tile(&config, &src_image, tile)?;
+                }
+
+                if let Some(kelvin) = white_balance {
+                    let gains = match kelvin {
+                        Some(kelvin) => temperature_gains(kelvin),
+                        None => auto_white_balance(&src_image),
+                    };
+                    src_image = apply_gains(&src_image, gains);
+                }
+
+                if let Some(channel) = channel {
+                    src_image = extract_channel(&src_image, channel);
+                }
+
+                let mut extra_headers = HeaderMap::new();
+                let bytes = match grid {
+                    Some(grid) => {
+                        extra_headers
+                            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                        split_grid(&config, &query, &src_image, grid, dst_mime, &encode_options)?
+                    }
+                    None => process_image(
+                        &config,
+                        &query,
+                        src_image,
+                        dst_mime,
+                        &encode_options,
+                        watermark.as_ref(),
+                        &mut extra_headers,
+                    )?,
+                };
+                Ok((bytes, extra_headers))
+            }
+        })
+        .await
+        .map_err(|e| ImageProviderError::Internal(format!("Failed to process image: {e}")))??;
+        // Small PNGs in particular tend to grow when re-encoded
+        let (bytes, extra_headers) = match source {
+            Some(source) if bytes.len() > source.len() => {
+                debug!(
+                    "Serving original {path:?}, processed output is larger ({} > {} bytes)",
+                    bytes.len(),
+                    source.len()
+                );
+                let mime = HeaderValue::from_static(raw_mime.to_mime_type());
+                let headers = HeaderMap::from_iter([(CONTENT_TYPE, mime)]);
+                (source, headers)
+            }
+            _ => (bytes, extra_headers),
+        };
+        if let Some(limit) = query.output_size_limit(&config) {
+            if bytes.len() as u64 > limit {
+                return Err(ImageProviderError::OutputTooLarge {
+                    bytes: bytes.len(),
+                    limit,
+                });
+            }
+        }
+        metrics.record_processing(started.elapsed());
+        if let Some(events) = &events {
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+            events.on_encode_complete(&path, bytes.len(), elapsed_ms);
+        }
+
+        let processed = ProcessedImage {
+            bytes,
+            headers: extra_headers,
+        };
+        if let Some(cache_dir) = &config.cache_dir {
+            let cache_path = disk_cache_path(cache_dir, &key);
+            match save_disk_cache(&cache_path, &processed).await {
+                Ok(()) if config.precompress => {
+                    // Brotli at its best level takes a while, the response need not wait for it
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = precompress_file(&cache_path) {
+                            warn!("Failed to precompress {cache_path:?}: {e}");
+                        }
+                    });
+                }
+                Ok(()) => {}
+                Err(e) => warn!("Failed to write {path:?} to the disk cache: {e}"),
+            }
+        }
+        Ok(processed)
+    }
+    .await;
+
+    // Cache the processed image
+    if let Ok(processed) = &processed {
+        cache.lock().await.cache_set(key, processed.clone());
+        if let Some(flight) = flight {
+            flight.finish(processed.clone());
+        }
+    }
+
+    trace!(
+        "Serving processed image: {path:?} (mime: {dst_mime:?}, size {:?}x{:?}, dpr: {dpr})",
+        dst_width.unwrap_or(0),
+        dst_height.unwrap_or(0)
+    );
+    serve_processed(headers, processed?, range, &if_none_match, &config).await
+}
+
+/// Respond with a processed image, honoring `If-None-Match` and `Range`
+async fn serve_processed(
+    mut headers: HeaderMap,
+    processed: ProcessedImage,
+    range: Option<Range>,
+    if_none_match: &Option<TypedHeader<IfNoneMatch>>,
+    config: &ResizeConfig,
+) -> Result<Response> {
+    headers.extend(processed.headers);
+    if let Some(response) = check_etag(&mut headers, hash(&processed.bytes), if_none_match) {
+        return Ok(response);
+    }
+    check_range(&range, processed.bytes.len() as u64, config)?;
+    let body = KnownSize::seek(Cursor::new(processed.bytes)).await.unwrap();
+    Ok((headers, Ranged::new(range, body)).into_response())
+}
+
+/// Reject a `Range` asking for more than `max_range_size` bytes of a `size` bytes body
+fn check_range(range: &Option<Range>, size: u64, config: &ResizeConfig) -> Result<()> {
+    let (Some(range), Some(limit)) = (range, config.max_range_size) else {
+        return Ok(());
+    };
+    let bytes = range
+        .satisfiable_ranges(size)
+        .map(|(start, end)| {
+            let start = match start {
+                Bound::Included(start) => start,
+                Bound::Excluded(start) => start + 1,
+                Bound::Unbounded => 0,
+            };
+            let end = match end {
+                Bound::Included(end) => end + 1,
+                Bound::Excluded(end) => end,
+                Bound::Unbounded => size,
+            };
+            end.min(size).saturating_sub(start)
+        })
+        .sum();
+    match bytes > limit {
+        true => Err(ImageProviderError::RangeTooLarge { bytes, limit }),
+        false => Ok(()),
+    }
+}
+
+/// Requests being processed, identical requests subscribe to the result instead
+type InFlight = DashMap<CacheKey, broadcast::Sender<ProcessedImage>>;
+
+/// Registration of the request processing a key, removed even if the request is cancelled
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    key: CacheKey,
+}
+
+impl InFlightGuard<'_> {
+    /// Hand the result to every waiting request
+    fn finish(self, processed: ProcessedImage) {
+        if let Some((_, sender)) = self.in_flight.remove(&self.key) {
+            // No receivers is fine, nobody was waiting
+            let _ = sender.send(processed);
+        }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.remove(&self.key);
+    }
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Tag the response, and answer `304 Not Modified` when the client already has it
+fn check_etag(
+    headers: &mut HeaderMap,
+    hash: u64,
+    if_none_match: &Option<TypedHeader<IfNoneMatch>>,
+) -> Option<Response> {
+    let etag: ETag = format!("\"{hash:016x}\"").parse().unwrap();
+    headers.typed_insert(etag.clone());
+    match if_none_match {
+        Some(TypedHeader(if_none_match)) if !if_none_match.precondition_passes(&etag) => {
+            Some((StatusCode::NOT_MODIFIED, headers.clone()).into_response())
+        }
+        _ => None,
+    }
+}
+
+fn process_image(
+    config: &ResizeConfig,
+    query: &ImageQuery,
+    src_image: DynamicImage,
+    dst_mime: OutputFormat,
+    encode_options: &EncodeOptions,
+    watermark: Option<&DynamicImage>,
+    headers: &mut HeaderMap,
+) -> Result<Bytes> {
+    let fit = query.fit()?;
+    let (dst_width, dst_height) = match dst_mime {
+        // The pyramid level is already the output size
+        _ if query.keeps_source_size() => (src_image.width(), src_image.height()),
+        OutputFormat::Ascii => match query.size() {
+            (None, None) => (80, 24),
+            dst => get_output_size(
+                (src_image.width(), src_image.height()),
+                dst,
+                1.0,
+                query.ce(),
+            ),
+        },
+        OutputFormat::Lqip => (
+            (src_image.width() / 100).max(10),
+            (src_image.height() / 100).max(10),
+        ),
+        OutputFormat::Image(_)
+        | OutputFormat::Auto
+        | OutputFormat::Jpeg2000
+        | OutputFormat::Raw(_) => get_output_size(
+            (src_image.width(), src_image.height()),
+            query.size(),
+            query.dpr(config),
+            query.ce(),
+        ),
+    };
+
+    let (dst_width, dst_height) = match fit {
+        Fit::Contain => contain_size(
+            (src_image.width(), src_image.height()),
+            (dst_width, dst_height),
+        ),
+        _ => (dst_width, dst_height),
+    };
+
+    check_output_size(config, (dst_width, dst_height))?;
+
+    let mut prescaled = match query.fast_sampling(config)? {
+        true => prescale(&src_image, dst_width, dst_height, fit)?,
+        false => None,
+    };
+    for (width, height) in query.chained_resize(config)? {
+        let step_src = prescaled.as_ref().unwrap_or(&src_image);
+        if width > step_src.width() || height > step_src.height() {
+            return Err(ImageProviderError::BadRequest(format!(
+                "chained_resize step {width}x{height} is larger than the image"
+            )));
+        }
+        let mut step = DynamicImage::new(width, height, step_src.color());
+        resize_image_fit(config, step_src, &mut step, fit)?;
+        prescaled = Some(step);
+    }
+
+    let mut dst_image = DynamicImage::new(dst_width, dst_height, src_image.color());
+    resize_image_fit(
+        config,
+        prescaled.as_ref().unwrap_or(&src_image),
+        &mut dst_image,
+        fit,
+    )?;
+    match (query.blur()?, query.sharpen()?) {
+        (Some(sigma), sharpen) => {
+            if sharpen.is_some() {
+                debug!("Ignoring sharpen, blur takes precedence");
+            }
+            dst_image = dst_image.blur(sigma);
+        }
+        (None, Some(sigma)) => dst_image = dst_image.unsharpen(sigma, SHARPEN_THRESHOLD),
+        (None, None) => {}
+    }
+    if let Some(tile_size) = query.mosaic()? {
+        dst_image = mosaic(&dst_image, tile_size)?;
+    }
+    if let Some(watermark) = watermark {
+        overlay_watermark(config, &mut dst_image, watermark)?;
+    }
+    if let Some((colors, dither)) = query.quantize()? {
+        dst_image = quantize_image(&dst_image, colors, dither);
+    }
+    #[cfg(feature = "pngquant")]
+    if let Some(quality) = query.pngquant()? {
+        dst_image = pngquant_image(&dst_image, quality)?;
+    }
+
+    Ok(match dst_mime {
+        OutputFormat::Image(_) | OutputFormat::Auto => {
+            let format = match dst_mime {
+                OutputFormat::Image(format) => format,
+                _ => {
+                    let format = select_format_by_content(&dst_image);
+                    let mime = OutputFormat::Image(format).mime_type();
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static(mime));
+                    let name = format.extensions_str()[0];
+                    headers.insert("x-actual-format", HeaderValue::from_static(name));
+                    format
+                }
+            };
+            let (format, bytes) = match (query.output_compare()?, query.output_format_chain()?) {
+                (Some(formats), _) => {
+                    encode_smallest(&formats, &dst_image, encode_options, headers)?
+                }
+                (None, Some(formats)) => {
+                    encode_first(&formats, &dst_image, encode_options, headers)?
+                }
+                (None, None) => (
+                    format,
+                    encode_with_options(format, &dst_image, encode_options)?,
+                ),
+            };
+            if query.quality_metrics() {
+                let (reference, output) = decode_with_reference(&src_image, &bytes, format, fit)?;
+                if flag(&query.psnr) {
+                    let psnr = quality::psnr(&reference.to_rgb8(), &output.to_rgb8());
+                    let psnr = format!("{psnr:.2}");
+                    headers.insert("x-psnr-db", HeaderValue::from_str(&psnr).unwrap());
+                }
+                if flag(&query.ssim) {
+                    let ssim = quality::ssim(&reference.to_luma8(), &output.to_luma8());
+                    let ssim = format!("{ssim:.4}");
+                    headers.insert("x-ssim", HeaderValue::from_str(&ssim).unwrap());
+                }
+            }
+            bytes
+        }
+        OutputFormat::Ascii => encode_ascii(&dst_image, &config.ascii_ramp)?,
+        OutputFormat::Jpeg2000 => encode_jpeg2000(&dst_image, encode_options)?,
+        OutputFormat::Raw(format) => {
+            headers.insert("x-image-width", dst_image.width().into());
+            headers.insert("x-image-height", dst_image.height().into());
+            headers.insert("x-pixel-format", HeaderValue::from_static(format.as_str()));
+            encode_raw(&dst_image, format)
+        }
+        OutputFormat::Lqip => encode_lqip(&dst_image, encode_options.background)?,
+    })
+}
+
+/// Pick an output format for `output=auto`: WebP for transparency, PNG for few colors (logos,
+/// diagrams), JPEG for photos and WebP for the rest
+fn select_format_by_content(img: &DynamicImage) -> ImageFormat {
+    /// Luma entropy in bits above which the image is treated as a photo
+    const PHOTO_ENTROPY: f64 = 7.0;
+
+    if img.color().has_alpha() && img.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX) {
+        return ImageFormat::WebP;
+    }
+
+    let sample = img.resize_exact(64, 64, imageops::FilterType::Nearest);
+    let colors: HashSet<_> = sample.to_rgb8().pixels().map(|pixel| pixel.0).collect();
+    if colors.len() < 256 {
+        return ImageFormat::Png;
+    }
+
+    let luma = sample.to_luma8();
+    let mut histogram = [0u32; 256];
+    for pixel in luma.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+    let total = luma.pixels().len() as f64;
+    let entropy: f64 = histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+    match entropy > PHOTO_ENTROPY {
+        true => ImageFormat::Jpeg,
+        false => ImageFormat::WebP,
+    }
+}
+
+/// Largest `blur`/`sharpen` sigma, the kernel grows with it
+const MAX_FILTER_SIGMA: f32 = 100.0;
+/// Smallest brightness difference `sharpen` enhances, so flat areas keep their noise level
+const SHARPEN_THRESHOLD: i32 = 1;
+
+fn filter_sigma(name: &str, sigma: Option<&str>) -> Result<Option<f32>> {
+    match sigma.map(str::parse::<f32>) {
+        None => Ok(None),
+        Some(Ok(sigma)) if sigma > 0.0 && sigma <= MAX_FILTER_SIGMA => Ok(Some(sigma)),
+        Some(_) => Err(ImageProviderError::BadRequest(format!(
+            "{name} must be a sigma above 0 and up to {MAX_FILTER_SIGMA}"
+        ))),
+    }
+}
+
+/// Pixelate by shrinking to one pixel per tile and scaling back up with nearest neighbor
+fn mosaic(image: &DynamicImage, tile_size: u32) -> Result<DynamicImage> {
+    let (width, height) = (image.width(), image.height());
+    let mut tiles = DynamicImage::new(
+        width.div_ceil(tile_size),
+        height.div_ceil(tile_size),
+        image.color(),
+    );
+    resize_image_with(ResizeAlg::Nearest, image, &mut tiles, None)?;
+
+    let mut pixelated = DynamicImage::new(width, height, image.color());
+    resize_image_with(ResizeAlg::Nearest, &tiles, &mut pixelated, None)?;
+    Ok(pixelated)
+}
+
+/// Blend `watermark` into the bottom-right corner of `image`, its longer side scaled to
+/// `watermark_scale` of the image's shorter side
+fn overlay_watermark(
+    config: &ResizeConfig,
+    image: &mut DynamicImage,
+    watermark: &DynamicImage,
+) -> Result<()> {
+    let shorter = image.width().min(image.height());
+    let target = ((shorter as f32 * config.watermark_scale.clamp(0., 1.)).round() as u32).max(1);
+    let (width, height) = contain_size((watermark.width(), watermark.height()), (target, target));
+
+    let mut scaled = DynamicImage::new_rgba8(width, height);
+    resize_image_with(
+        config.resize_algorithm(),
+        &DynamicImage::ImageRgba8(watermark.to_rgba8()),
+        &mut scaled,
+        None,
+    )?;
+    let opacity = config.watermark_opacity.clamp(0., 1.);
+    let mut scaled = scaled.into_rgba8();
+    for Rgba([.., alpha]) in scaled.pixels_mut() {
+        *alpha = (*alpha as f32 * opacity).round() as u8;
+    }
+
+    // Keep a small gap to the edges, like a photo credit
+    let margin = i64::from(shorter / 50);
+    let x = i64::from(image.width()) - i64::from(width) - margin;
+    let y = i64::from(image.height()) - i64::from(height) - margin;
+    imageops::overlay(image, &DynamicImage::ImageRgba8(scaled), x, y);
+    Ok(())
+}
+
+/// Reduce to a palette of `colors`, optionally dithering to hide banding
+fn quantize_image(image: &DynamicImage, colors: usize, dither: Dither) -> DynamicImage {
+    let image = image.to_rgba8();
+    let pixels: Vec<Color> = image
+        .pixels()
+        .map(|&Rgba([r, g, b, a])| Color::new(r, g, b, a))
+        .collect();
+
+    let colorspace = SimpleColorSpace::default();
+    let histogram = pixels.iter().cloned().collect();
+    let palette = generate_palette(&histogram, &colorspace, &optimizer::KMeans, colors);
+
+    let ditherer: &dyn Ditherer = match dither {
+        Dither::None => &ditherer::None,
+        Dither::FloydSteinberg => &ditherer::FloydSteinberg::new(),
+        Dither::Ordered => &ditherer::Ordered,
+    };
+    let indices =
+        Remapper::new(&palette, &colorspace, ditherer).remap(&pixels, image.width() as usize);
+
+    let pixels = indices
+        .into_iter()
+        .flat_map(|index| {
+            let Color { r, g, b, a } = palette[index as usize];
+            [r, g, b, a]
+        })
+        .collect();
+    DynamicImage::ImageRgba8(RgbaImage::from_raw(image.width(), image.height(), pixels).unwrap())
+}
+
+/// Reduce to at most 256 colors with libimagequant
+#[cfg(feature = "pngquant")]
+fn pngquant_image(image: &DynamicImage, quality: u8) -> Result<DynamicImage> {
+    let quantize_error = |e: imagequant::Error| {
+        ImageProviderError::Internal(format!("Failed to quantize image: {e}"))
+    };
+
+    let image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+    let pixels: Vec<imagequant::RGBA> = image
+        .pixels()
+        .map(|&Rgba([r, g, b, a])| imagequant::RGBA::new(r, g, b, a))
+        .collect();
+
+    let mut attributes = imagequant::new();
+    attributes.set_quality(0, quality).map_err(quantize_error)?;
+    let mut liq_image = attributes
+        .new_image(pixels, width as usize, height as usize, 0.0)
+        .map_err(quantize_error)?;
+    let mut result = attributes
+        .quantize(&mut liq_image)
+        .map_err(quantize_error)?;
+    let (palette, indices) = result.remapped(&mut liq_image).map_err(quantize_error)?;
+
+    let pixels = indices
+        .into_iter()
+        .flat_map(|index| {
+            let color = palette[index as usize];
+            [color.r, color.g, color.b, color.a]
+        })
+        .collect();
+    Ok(DynamicImage::ImageRgba8(
+        RgbaImage::from_raw(width, height, pixels).unwrap(),
+    ))
+}
+
+/// Bilinear downsample to twice the target size, so the final resize only has a 2x step left
+fn prescale(
+    src_image: &DynamicImage,
+    width: u32,
+    height: u32,
+    fit: Fit,
+) -> Result<Option<DynamicImage>> {
+    let (width, height) = (width.saturating_mul(2), height.saturating_mul(2));
+    if src_image.width() <= width || src_image.height() <= height {
+        return Ok(None);
+    }
+
+    let mut prescaled = DynamicImage::new(width, height, src_image.color());
+    let algorithm = ResizeAlg::Convolution(FilterType::Bilinear);
+    resize_image_with(algorithm, src_image, &mut prescaled, fit.centering())?;
+    Ok(Some(prescaled))
+}
+
+/// Encode in every format and keep the smallest, reporting all sizes in `X-Format-Comparison`
+fn encode_smallest(
+    formats: &[(&str, ImageFormat)],
+    image: &DynamicImage,
+    options: &EncodeOptions,
+    headers: &mut HeaderMap,
+) -> Result<(ImageFormat, Bytes)> {
+    let mut smallest: Option<(ImageFormat, Bytes)> = None;
+    let mut comparison = Vec::with_capacity(formats.len());
+    for &(name, format) in formats {
+        let bytes = encode_with_options(format, image, options)?;
+        debug!("Encoded {name}: {} bytes", bytes.len());
+        comparison.push(format!("{name}={}", bytes.len()));
+        if smallest
+            .as_ref()
+            .is_none_or(|(_, best)| bytes.len() < best.len())
+        {
+            smallest = Some((format, bytes));
+        }
+    }
+
+    let (format, bytes) = smallest.ok_or(ImageProviderError::BadRequest(
+        "No output formats to compare".to_string(),
+    ))?;
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(OutputFormat::Image(format).mime_type()),
+    );
+    headers.insert(
+        "x-format-comparison",
+        HeaderValue::from_str(&comparison.join(",")).unwrap(),
+    );
+    Ok((format, bytes))
+}
+
+/// Encode with the first format that succeeds, reporting it in `X-Actual-Format`
+fn encode_first(
+    formats: &[(&str, ImageFormat)],
+    image: &DynamicImage,
+    options: &EncodeOptions,
+    headers: &mut HeaderMap,
+) -> Result<(ImageFormat, Bytes)> {
+    let mut error = ImageProviderError::BadRequest("No output formats to try".to_string());
+    for &(name, format) in formats {
+        match encode_with_options(format, image, options) {
+            Ok(bytes) => {
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(OutputFormat::Image(format).mime_type()),
+                );
+                headers.insert("x-actual-format", HeaderValue::from_str(name).unwrap());
+                return Ok((format, bytes));
+            }
+            Err(e) => {
+                debug!("Failed to encode {name}, trying the next format: {}", e);
+                error = e;
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Decode the encoded output again, along with the source scaled to its size by nearest neighbor
+fn decode_with_reference(
+    src_image: &DynamicImage,
+    bytes: &[u8],
+    format: ImageFormat,
+    fit: Fit,
+) -> Result<(DynamicImage, DynamicImage)> {
+    // The output was just encoded here, failing to read it back is not the source's fault
+    let output = load_from_memory_with_format(bytes, format).map_err(|e| {
+        ImageProviderError::Internal(format!("Failed to decode the encoded output: {e}"))
+    })?;
+
+    let src_image = DynamicImage::ImageRgb8(src_image.to_rgb8());
+    let mut reference = DynamicImage::new(output.width(), output.height(), src_image.color());
+    resize_image_with(
+        ResizeAlg::Nearest,
+        &src_image,
+        &mut reference,
+        fit.centering(),
+    )?;
+
+    Ok((reference, output))
+}
+
+/// Resize `count` frames to `w`x`h` and lay them out side by side
+fn thumbnail_strip(
+    config: &ResizeConfig,
+    query: &ImageQuery,
+    frames: &[(u32, RgbaImage)],
+    count: usize,
+    interval: Option<u32>,
+) -> Result<DynamicImage> {
+    let thumbnails = (0..count)
+        .map(|i| {
+            let index = match interval {
+                // The last frame that starts before the sample time
+                Some(interval) => {
+                    let time = interval.saturating_mul(i as u32);
+                    frames.partition_point(|(start, _)| *start <= time).max(1) - 1
+                }
+                None => i * frames.len() / count,
+            };
+            let frame = DynamicImage::ImageRgba8(frames[index].1.clone());
+            let (width, height) = get_output_size(
+                (frame.width(), frame.height()),
+                query.size(),
+                query.dpr(config),
+                query.ce(),
+            );
+            check_output_size(config, (width, height))?;
+            let mut thumbnail = DynamicImage::new(width, height, frame.color());
+            resize_image(config, &frame, &mut thumbnail)?;
+            Ok(thumbnail)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    stitch_images(config, thumbnails, StitchDirection::Horizontal)
+}
+
+/// Gray world white balance, scale each channel so the average color is neutral
+fn auto_white_balance(img: &DynamicImage) -> (f32, f32, f32) {
+    let (mut r, mut g, mut b) = (0.0_f64, 0.0_f64, 0.0_f64);
+    for pixel in img.to_rgb8().pixels() {
+        r += pixel[0] as f64;
+        g += pixel[1] as f64;
+        b += pixel[2] as f64;
+    }
+
+    let gray = (r + g + b) / 3.0;
+    let gain = |channel: f64| {
+        if channel > 0.0 {
+            (gray / channel) as f32
+        } else {
+            1.0
+        }
+    };
+    (gain(r), gain(g), gain(b))
+}
+
+/// Gains that neutralize light of the given color temperature, relative to 6500K
+fn temperature_gains(kelvin: u32) -> (f32, f32, f32) {
+    let (r, g, b) = kelvin_to_rgb(kelvin);
+    let (white_r, white_g, white_b) = kelvin_to_rgb(6500);
+    (white_r / r, white_g / g, white_b / b)
+}
+
+/// Approximate color of a black body along the Planckian locus (Tanner Helland's fit)
+fn kelvin_to_rgb(kelvin: u32) -> (f32, f32, f32) {
+    let t = kelvin as f32 / 100.0;
+    let r = match t {
+        ..=66.0 => 255.0,
+        _ => 329.698_73 * (t - 60.0).powf(-0.133_204_76),
+    };
+    let g = match t {
+        ..=66.0 => 99.470_8 * t.ln() - 161.119_57,
+        _ => 288.122_16 * (t - 60.0).powf(-0.075_514_846),
+    };
+    let b = match t {
+        66.0.. => 255.0,
+        ..=19.0 => 0.0,
+        _ => 138.517_73 * (t - 10.0).ln() - 305.044_8,
+    };
+    // Keep a floor so very warm light does not divide by zero
+    let clamp = |channel: f32| channel.clamp(1.0, 255.0);
+    (clamp(r), clamp(g), clamp(b))
+}
+
+fn apply_gains(image: &DynamicImage, (r, g, b): (f32, f32, f32)) -> DynamicImage {
+    let mut image = image.to_rgba8();
+    for pixel in image.pixels_mut() {
+        for (channel, gain) in pixel.0.iter_mut().zip([r, g, b]) {
+            *channel = (*channel as f32 * gain).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+/// One RGBA channel as a grayscale image
+fn extract_channel(image: &DynamicImage, channel: usize) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let pixels = image
+        .to_rgba8()
+        .into_raw()
+        .into_iter()
+        .skip(channel)
+        .step_by(4)
+        .collect();
+    DynamicImage::ImageLuma8(GrayImage::from_raw(width, height, pixels).unwrap())
+}
+
+/// Cut out `(x, y, width, height)`, which must lie within the image
+fn crop_image(
+    image: &DynamicImage,
+    (x, y, width, height): (u32, u32, u32, u32),
+) -> Result<DynamicImage> {
+    let fits = x
+        .checked_add(width)
+        .is_some_and(|right| right <= image.width())
+        && y.checked_add(height)
+            .is_some_and(|bottom| bottom <= image.height());
+    if !fits {
+        return Err(ImageProviderError::BadRequest(format!(
+            "Crop {x},{y},{width},{height} is outside the {}x{} image",
+            image.width(),
+            image.height()
+        )));
     }
+    Ok(image.crop_imm(x, y, width, height))
+}
 
-    fn dpr(&self) -> f32 {
-        self.dpr
-            .clone()
-            .unwrap_or("1".into())
-            .parse::<f32>()
-            .unwrap_or(1.0)
-            .clamp(0.5, 5.)
+/// Cut off `[top, right, bottom, left]` margins given in percent of the image size
+fn crop_margins(
+    image: &DynamicImage,
+    [top, right, bottom, left]: [f32; 4],
+) -> Result<DynamicImage> {
+    let (width, height) = (image.width(), image.height());
+    let px = |pct: f32, extent: u32| (pct / 100.0 * extent as f32) as u32;
+    let (top, bottom) = (px(top, height), px(bottom, height));
+    let (left, right) = (px(left, width), px(right, width));
+
+    let cropped_width = width.saturating_sub(left + right);
+    let cropped_height = height.saturating_sub(top + bottom);
+    if cropped_width == 0 || cropped_height == 0 {
+        return Err(ImageProviderError::BadRequest(
+            "crop_pct leaves nothing of the image".to_string(),
+        ));
     }
+    Ok(image.crop_imm(left, top, cropped_width, cropped_height))
+}
 
-    fn ce(&self) -> bool {
-        match self.ce.as_deref() {
-            Some("0") | Some("false") | Some("no") => false,
-            Some(_) => true,
-            None => false,
-        }
+const MAX_TILE_ZOOM: u32 = 24;
+
+/// Cut the `(zoom, x, y)` tile out of a source covering the whole map, scaled to `tile_size`
+fn map_tile(
+    config: &ResizeConfig,
+    image: &DynamicImage,
+    (zoom, x, y): (u32, u32, u32),
+) -> Result<DynamicImage> {
+    let tiles = 1u64 << zoom;
+    let bounds = |index: u32, extent: u32| {
+        let start = index as u64 * extent as u64 / tiles;
+        let end = (index as u64 + 1) * extent as u64 / tiles;
+        // Past the source resolution a tile is smaller than a pixel, keep the pixel it falls in
+        let start = start.min(extent as u64 - 1);
+        (start as u32, (end.max(start + 1) - start) as u32)
+    };
+    let (left, width) = bounds(x, image.width());
+    let (top, height) = bounds(y, image.height());
+    let region = image.crop_imm(left, top, width, height);
+
+    let size = config.tile_size;
+    let mut tile = DynamicImage::new(size, size, image.color());
+    resize_image_fit(config, &region, &mut tile, Fit::Fill)?;
+    Ok(tile)
+}
+
+/// Blur and subsample by half `levels` times
+fn gaussian_pyramid(mut image: DynamicImage, levels: u8) -> DynamicImage {
+    const SIGMA: f32 = 1.0;
+    for _ in 0..levels {
+        let (width, height) = ((image.width() / 2).max(1), (image.height() / 2).max(1));
+        image = image
+            .blur(SIGMA)
+            .resize_exact(width, height, imageops::FilterType::Nearest);
     }
+    image
 }
 
-async fn provide_images(
-    State(ImageState {
-        root,
-        config,
-        cache,
-    }): State<ImageState>,
-    Query(query): Query<ImageQuery>,
-    Path(path): Path<PathBuf>,
-    range: Option<TypedHeader<Range>>,
-) -> Result<Response> {
-    let (path, raw_mime) = get_path_and_mime(root, path)?;
-    let dst_mime = query.output()?.unwrap_or(raw_mime);
-    let (dst_width, dst_height) = query.size();
-    let dpr = query.dpr();
-    let ce = query.ce();
+/// Scale every image to the first one's height (or width when stacking vertically)
+/// and lay them out next to each other
+fn stitch_images(
+    config: &ResizeConfig,
+    images: Vec<DynamicImage>,
+    direction: StitchDirection,
+) -> Result<DynamicImage> {
+    let (first_width, first_height) = (images[0].width(), images[0].height());
 
-    debug!(
-        "Processing image: {path:?} to mime: {dst_mime:?}, size: {:?}x{:?}, dpr: {dpr}, ce: {ce}",
-        dst_width.unwrap_or(0),
-        dst_height.unwrap_or(0)
-    );
+    let mut scaled = Vec::with_capacity(images.len());
+    for image in images {
+        let image = DynamicImage::ImageRgba8(image.to_rgba8());
+        let size = match direction {
+            StitchDirection::Horizontal => (None, Some(first_height)),
+            StitchDirection::Vertical => (Some(first_width), None),
+        };
+        let (width, height) = get_output_size((image.width(), image.height()), size, 1.0, false);
+        let mut dst_image = DynamicImage::new(width, height, image.color());
+        resize_image(config, &image, &mut dst_image)?;
+        scaled.push(dst_image);
+    }
 
-    let range = range.map(|TypedHeader(range)| range);
-    let headers = get_response_headers(&dst_mime);
+    let (width, height) = match direction {
+        StitchDirection::Horizontal => (scaled.iter().map(|i| i.width()).sum(), first_height),
+        StitchDirection::Vertical => (first_width, scaled.iter().map(|i| i.height()).sum()),
+    };
+    let mut canvas = DynamicImage::new_rgba8(width, height);
 
-    // If no resizing is needed, serve the original file directly
-    let eq_raw = dst_width.is_none()
-        && dst_height.is_none()
-        && f32::EPSILON > (dpr - 1.0)
-        && raw_mime == dst_mime;
-    let exclude = matches!(raw_mime, image::ImageFormat::Ico | image::ImageFormat::Gif);
-    if eq_raw || exclude {
-        trace!("Serving original image: {path:?}");
-        let file = load_file(&path).await?;
-        let body = KnownSize::file(file).await.unwrap();
-        let ranged = Ranged::new(range, body);
-        return Ok((headers, ranged).into_response());
+    let mut offset = 0;
+    for image in scaled {
+        match direction {
+            StitchDirection::Horizontal => {
+                imageops::overlay(&mut canvas, &image, offset, 0);
+                offset += image.width() as i64;
+            }
+            StitchDirection::Vertical => {
+                imageops::overlay(&mut canvas, &image, 0, offset);
+                offset += image.height() as i64;
+            }
+        }
     }
 
-    if let Some(cached) = cache.lock().await.cache_get(&(path.clone(), query.clone())) {
-        trace!(
-            "Serving cached image: {path:?} (mime: {dst_mime:?}, size {:?}x{:?}, dpr: {dpr})",
-            dst_width.unwrap_or(0),
-            dst_height.unwrap_or(0)
-        );
-        let body = KnownSize::seek(Cursor::new(cached.clone())).await.unwrap();
-        return Ok((headers, Ranged::new(range, body)).into_response());
+    Ok(canvas)
+}
+
+/// Absolute per channel difference, `other` is resized to match `image` first
+fn diff_images(
+    config: &ResizeConfig,
+    image: &DynamicImage,
+    other: &DynamicImage,
+) -> Result<DynamicImage> {
+    let other = DynamicImage::ImageRgb8(other.to_rgb8());
+    let mut resized = DynamicImage::new(image.width(), image.height(), other.color());
+    resize_image(config, &other, &mut resized)?;
+
+    let mut diff = image.to_rgb8();
+    for (pixel, other) in diff.pixels_mut().zip(resized.to_rgb8().pixels()) {
+        for (a, b) in pixel.0.iter_mut().zip(other.0) {
+            *a = a.abs_diff(b);
+        }
     }
+    Ok(DynamicImage::ImageRgb8(diff))
+}
 
-    let file = load_file(&path).await?;
-    let src_image = load_image(file).await?;
+#[derive(Serialize)]
+struct GridManifest {
+    cols: u32,
+    rows: u32,
+    format: &'static str,
+    tiles: Vec<GridTile>,
+}
 
-    let (dst_width, dst_height) = get_output_size(
-        (src_image.width(), src_image.height()),
-        (dst_width, dst_height),
-        dpr,
-        ce,
-    );
+#[derive(Serialize)]
+struct GridTile {
+    col: u32,
+    row: u32,
+    width: u32,
+    height: u32,
+    data: String,
+}
 
-    let mut dst_image = Image::new(dst_width, dst_height, src_image.pixel_type().unwrap());
-    resize_image(&config, &src_image, &mut dst_image)?;
+/// Split the image into `cols`x`rows` tiles, each resized and encoded on its own,
+/// and return them as a JSON manifest of base64 encoded tiles
+fn split_grid(
+    config: &ResizeConfig,
+    query: &ImageQuery,
+    src_image: &DynamicImage,
+    (cols, rows): (u32, u32),
+    dst_mime: OutputFormat,
+    encode_options: &EncodeOptions,
+) -> Result<Bytes> {
+    let OutputFormat::Image(format) = dst_mime else {
+        return Err(ImageProviderError::BadRequest(
+            "grid only supports image output formats".to_string(),
+        ));
+    };
 
-    let bytes = encode_image(dst_mime, &dst_image, src_image.color())?;
+    let (src_width, src_height) = (src_image.width(), src_image.height());
+    if cols > src_width || rows > src_height {
+        return Err(ImageProviderError::BadRequest(format!(
+            "grid {cols}x{rows} is larger than the image ({src_width}x{src_height})"
+        )));
+    }
 
-    // Cache the processed image
-    cache
-        .lock()
-        .await
-        .cache_set((path.clone(), query), bytes.clone());
+    let mut tiles = Vec::with_capacity((cols * rows) as usize);
+    for row in 0..rows {
+        for col in 0..cols {
+            let x = col * src_width / cols;
+            let y = row * src_height / rows;
+            let tile_width = (col + 1) * src_width / cols - x;
+            let tile_height = (row + 1) * src_height / rows - y;
+            let tile = src_image.crop_imm(x, y, tile_width, tile_height);
 
-    let body = KnownSize::seek(Cursor::new(bytes)).await.unwrap();
+            let (width, height) = get_output_size(
+                (tile_width, tile_height),
+                query.size(),
+                query.dpr(config),
+                query.ce(),
+            );
+            check_output_size(config, (width, height))?;
+            let mut dst_image = DynamicImage::new(width, height, tile.color());
+            resize_image(config, &tile, &mut dst_image)?;
+            let bytes = encode_with_options(format, &dst_image, encode_options)?;
 
-    trace!(
-        "Serving processed image: {path:?} (mime: {dst_mime:?}, size {dst_width:?}x{dst_height:?}, dpr: {dpr})"
-    );
-    Ok((headers, Ranged::new(range, body)).into_response())
+            tiles.push(GridTile {
+                col,
+                row,
+                width,
+                height,
+                data: BASE64_STANDARD.encode(bytes),
+            });
+        }
+    }
+
+    let manifest = GridManifest {
+        cols,
+        rows,
+        format: format.to_mime_type(),
+        tiles,
+    };
+    serde_json::to_vec(&manifest)
+        .map(Bytes::from)
+        .map_err(|e| ImageProviderError::Internal(format!("Failed to serialize grid: {e}")))
 }
 
-fn get_path_and_mime(root: PathBuf, rel_path: PathBuf) -> Result<(PathBuf, ImageFormat)> {
+/// Resolve a requested path below `root`, or reject it.
+///
+/// `raw` is taken as already decoded by the router, so `%` is an ordinary character here. Only
+/// plain names are allowed, no `..`, root or prefix. An existing path is canonicalized and must
+/// stay below the canonical `root`, which also rules out symlinks leading outside of it. A
+/// missing one is returned as is for the caller to report.
+pub fn sanitize_path(root: &std::path::Path, raw: &str) -> Result<PathBuf> {
     use std::path::Component;
 
-    let cleaned = path_clean::clean(rel_path);
-
-    let is_valid = cleaned
+    if raw.contains('\0') {
+        return Err(ImageProviderError::BadRequest(
+            "Null bytes are not allowed in paths".to_string(),
+        ));
+    }
+    let relative = std::path::Path::new(raw);
+    let is_valid = relative
         .components()
         .all(|c| matches!(c, Component::Normal(_)));
     if !is_valid {
-        return Err((StatusCode::NOT_FOUND, "File not found".to_string()));
+        return Err(ImageProviderError::NotFound);
+    }
+
+    let root = root
+        .canonicalize()
+        .map_err(|_| ImageProviderError::NotFound)?;
+    let path = root.join(relative);
+    match path.canonicalize() {
+        Ok(path) if path.starts_with(&root) => Ok(path),
+        Ok(_) => Err(ImageProviderError::NotFound),
+        Err(_) => Ok(path),
     }
+}
 
-    let path = root.join(&cleaned);
+fn get_path_and_mime(root: PathBuf, rel_path: &str) -> Result<(PathBuf, ImageFormat)> {
+    let path = sanitize_path(&root, rel_path)?;
+    if !path.is_file() {
+        return Err(ImageProviderError::NotFound);
+    }
 
-    if !path.exists() || !path.is_file() {
-        return Err((StatusCode::NOT_FOUND, "File not found".to_string()));
+    // Vector sources are reported as the PNG they are rasterized to
+    if is_svg(&path) {
+        return match cfg!(feature = "svg") {
+            true => Ok((path, ImageFormat::Png)),
+            false => Err(ImageProviderError::UnsupportedMediaType(
+                "SVG sources are unavailable, compile with --features svg".to_string(),
+            )),
+        };
     }
 
-    match find_image_mime(MimeGuess::from_path(&path)) {
+    // The extension is enough in the common case, only read the file without one
+    match find_image_mime(MimeGuess::from_path(&path)).or_else(|| sniff_format(&path)) {
         Some(mime) => Ok((path.clone(), mime)),
-        None => Err((StatusCode::BAD_REQUEST, "Unsupported file type".to_string())),
+        None => Err(ImageProviderError::UnsupportedFormat(
+            path.extension()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        )),
+    }
+}
+
+fn is_svg(path: &std::path::Path) -> bool {
+    MimeGuess::from_path(path)
+        .iter()
+        .any(|mime| mime.essence_str() == "image/svg+xml")
+}
+
+/// Guess the format from the magic bytes at the start of the file
+fn sniff_format(path: &PathBuf) -> Option<ImageFormat> {
+    use std::io::Read;
+
+    let mut header = Vec::with_capacity(512);
+    std::fs::File::open(path)
+        .and_then(|file| file.take(512).read_to_end(&mut header))
+        .ok()?;
+    image::guess_format(&header).ok()
+}
+
+/// Whether a WebP file is animated, from the animation flag of its extended (`VP8X`) header,
+/// which is set whenever an `ANIM` chunk follows
+fn is_animated_webp(path: &PathBuf) -> Result<bool> {
+    use std::io::Read;
+
+    // `RIFF`, size, `WEBP`, then the first chunk's type, size and flags
+    let mut header = [0; 21];
+    match std::fs::File::open(path)?.read_exact(&mut header) {
+        Ok(()) => Ok(&header[12..16] == b"VP8X" && header[20] & 0x02 != 0),
+        // Too short for an extended header, the decoder reports what is wrong with it
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
     }
 }
 
+/// Parse a comma separated list of image extensions, keeping the names as given
+fn parse_formats(formats: &str) -> Result<Vec<(&str, ImageFormat)>> {
+    formats
+        .split(',')
+        .map(|ext| {
+            find_image_mime(MimeGuess::from_ext(ext))
+                .map(|format| (ext, format))
+                .ok_or_else(|| ImageProviderError::UnsupportedFormat(ext.to_string()))
+        })
+        .collect()
+}
+
+/// First of `candidates` in `Accept` by quality, `None` when only wildcards or other types match
+fn negotiate_format(accept: &str, candidates: &[ImageFormat]) -> Option<ImageFormat> {
+    let mut types: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let mime = params.next().filter(|mime| !mime.is_empty())?;
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            Some((mime, quality))
+        })
+        .filter(|&(_, quality)| quality > 0.0)
+        .collect();
+    // Stable, so equal qualities keep the client's order
+    types.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    types
+        .into_iter()
+        .filter_map(|(mime, _)| ImageFormat::from_mime_type(mime))
+        .find(|format| candidates.contains(format))
+}
+
 fn find_image_mime(mime: MimeGuess) -> Option<ImageFormat> {
     mime.into_iter()
         .flat_map(|m| ImageFormat::from_mime_type(&m))
         .next()
 }
 
-fn get_response_headers(image_format: &ImageFormat) -> HeaderMap {
-    debug!("Setting response headers for format: {image_format:?}");
+fn get_response_headers(output_format: &OutputFormat, cache_control: &str) -> Result<HeaderMap> {
+    debug!("Setting response headers for format: {output_format:?}");
     let mut headers = HeaderMap::new();
     for (name, value) in [
-        (CONTENT_TYPE, image_format.to_mime_type()),
-        (CACHE_CONTROL, "public, max-age=31536000"),
+        (CONTENT_TYPE, output_format.mime_type()),
+        (CACHE_CONTROL, cache_control),
         (X_CONTENT_TYPE_OPTIONS, "nosniff"),
     ] {
         debug!("Setting header: {name}: {value}");
-        headers.insert(name, HeaderValue::from_static(value));
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| ImageProviderError::Internal(format!("Invalid header value: {value}")))?;
+        headers.insert(name, value);
     }
     debug!("Response headers set: {headers:?}");
-    headers
+    Ok(headers)
 }
 
-async fn load_file(path: &PathBuf) -> Result<File> {
+async fn load_file(path: &PathBuf, config: &ResizeConfig) -> Result<File> {
     debug!("Loading file: {path:?}");
-    File::open(&path).await.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to read image".to_string(),
-        )
+    let file = File::open(&path).await?;
+    if let Some(limit) = config.max_source_file_bytes {
+        let bytes = file.metadata().await?.len();
+        if bytes > limit {
+            return Err(ImageProviderError::SourceTooLarge { bytes, limit });
+        }
+    }
+    Ok(file)
+}
+
+/// File of a cache entry in `cache_dir`, named by the SHA-256 of the key
+fn disk_cache_path(cache_dir: &std::path::Path, (path, query, client_ip): &CacheKey) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(path.as_os_str().as_encoded_bytes());
+    hasher.update(serde_json::to_vec(query).unwrap_or_default());
+    if let Some(client_ip) = client_ip {
+        hasher.update(client_ip.to_string());
+    }
+    let digest: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    cache_dir.join(format!("{digest}.bin"))
+}
+
+/// Read a processed image from a disk cache entry, unless `source` changed since it was written
+async fn load_disk_cache(
+    cache_path: &std::path::Path,
+    source: &std::path::Path,
+) -> Option<ProcessedImage> {
+    let cached = tokio::fs::metadata(cache_path)
+        .await
+        .ok()?
+        .modified()
+        .ok()?;
+    let source = tokio::fs::metadata(source).await.ok()?.modified().ok()?;
+    if source > cached {
+        debug!("Disk cache entry is stale: {cache_path:?}");
+        return None;
+    }
+
+    // A line of JSON encoded headers, then the image
+    let data = Bytes::from(tokio::fs::read(cache_path).await.ok()?);
+    let split = data.iter().position(|&byte| byte == b'\n')?;
+    let headers: Vec<(String, String)> = serde_json::from_slice(&data[..split]).ok()?;
+    let headers = headers
+        .into_iter()
+        .map(|(name, value)| Some((name.parse().ok()?, value.parse().ok()?)))
+        .collect::<Option<HeaderMap>>()?;
+    Some(ProcessedImage {
+        bytes: data.slice(split + 1..),
+        headers,
     })
 }
 
-fn get_output_size(
+async fn clear_disk_cache(cache_dir: &std::path::Path) -> std::io::Result<()> {
+    let mut entries = match tokio::fs::read_dir(cache_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .path()
+            .extension()
+            .is_some_and(|ext| ext == "bin" || ext == "br")
+        {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn save_disk_cache(
+    cache_path: &std::path::Path,
+    processed: &ProcessedImage,
+) -> std::io::Result<()> {
+    let headers: Vec<(&str, &str)> = processed
+        .headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect();
+    let mut data = serde_json::to_vec(&headers)?;
+    data.push(b'\n');
+    data.extend_from_slice(&processed.bytes);
+
+    // Write next to the entry and rename, so readers never see half a file
+    let partial = cache_path.with_extension("tmp");
+    if let Some(cache_dir) = cache_path.parent() {
+        tokio::fs::create_dir_all(cache_dir).await?;
+    }
+    tokio::fs::write(&partial, data).await?;
+    tokio::fs::rename(&partial, cache_path).await
+}
+
+/// Write a Brotli compressed copy of the disk cache entry at `path` next to it, as `.br`.
+/// Only the image is compressed, the headers stay readable by [`load_disk_cache`]
+fn precompress_file(path: &std::path::Path) -> Result<PathBuf> {
+    let data = std::fs::read(path)?;
+    let split = data
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or_else(|| ImageProviderError::Internal(format!("Invalid cache entry: {path:?}")))?;
+    let mut compressed = data[..=split].to_vec();
+    let params = BrotliEncoderParams {
+        quality: 11,
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut &data[split + 1..], &mut compressed, &params)?;
+
+    let compressed_path = path.with_extension("br");
+    let partial = path.with_extension("br.tmp");
+    std::fs::write(&partial, compressed)?;
+    std::fs::rename(&partial, &compressed_path)?;
+    Ok(compressed_path)
+}
+
+/// Whether `Accept-Encoding` allows `br`, ignoring the preference between encodings
+fn accepts_brotli(request_headers: &HeaderMap) -> bool {
+    request_headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("br"))
+                && params.all(
+                    |param| !matches!(param.strip_prefix("q="), Some(q) if q.parse() == Ok(0.0)),
+                )
+        })
+}
+
+/// Size an image of `src` pixels is resized to for the `w`/`h` in `dst`, the device pixel ratio
+/// `dpr` and `ce`, the same as the `w`, `h`, `dpr` and `ce` query parameters.
+///
+/// With only one side in `dst` the other keeps the aspect ratio of `src`, without either the
+/// output is as large as `src`. Both are then scaled by `dpr`. `ce` stops an enlargement at
+/// the size of `src`, keeping the aspect ratio that was asked for. Neither side is below `1`.
+///
+/// ```
+/// use image_provider::get_output_size;
+///
+/// assert_eq!(get_output_size((800, 600), (Some(300), None), 1.0, false), (300, 225));
+/// assert_eq!(get_output_size((800, 600), (Some(300), None), 2.0, false), (600, 450));
+/// assert_eq!(get_output_size((800, 600), (Some(100), Some(100)), 1.0, false), (100, 100));
+/// // Not wider than the source
+/// assert_eq!(get_output_size((800, 600), (Some(2000), Some(1000)), 1.0, true), (800, 400));
+/// assert_eq!(get_output_size((1, 100), (None, Some(1)), 1.0, false), (1, 1));
+/// ```
+pub fn get_output_size(
     src: (u32, u32),
     dst: (Option<u32>, Option<u32>),
     dpr: f32,
@@ -268,76 +3495,648 @@ fn get_output_size(
         }
     }
 
-    (width, height)
+    // A side rounded away on an extreme aspect ratio still gets a pixel
+    (width.max(1), height.max(1))
 }
 
-async fn load_image(mut file: File) -> Result<DynamicImage> {
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).await.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to read image".to_string(),
-        )
-    })?;
-    load_from_memory(&buffer).map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to decode image: {e}"),
-        )
+/// Reject output sizes above `max_width` x `max_height` before allocating them
+fn check_output_size(config: &ResizeConfig, (width, height): (u32, u32)) -> Result<()> {
+    if width > config.max_width || height > config.max_height {
+        return Err(ImageProviderError::BadRequest(format!(
+            "Output size {width}x{height} exceeds the maximum of {}x{}",
+            config.max_width, config.max_height
+        )));
+    }
+    Ok(())
+}
+
+/// Largest size within `dst` that keeps the aspect ratio of `src`
+fn contain_size(src: (u32, u32), dst: (u32, u32)) -> (u32, u32) {
+    let scale = f32::min(dst.0 as f32 / src.0 as f32, dst.1 as f32 / src.1 as f32);
+    (
+        ((src.0 as f32 * scale).round() as u32).clamp(1, dst.0.max(1)),
+        ((src.1 as f32 * scale).round() as u32).clamp(1, dst.1.max(1)),
+    )
+}
+
+/// Decode every frame of an animated GIF, PNG or WebP along with its start time in ms,
+/// other images decode to a single frame
+async fn load_frames(file: File, format: ImageFormat) -> Result<Vec<(u32, RgbaImage)>> {
+    let reader = BufReader::new(SyncIoBridge::new(file));
+    tokio::task::spawn_blocking(move || {
+        let decode_error = ImageProviderError::DecodeError;
+        let single = |image: DynamicImage| Ok(vec![(0, image.to_rgba8())]);
+
+        let frames = match format {
+            ImageFormat::Gif => GifDecoder::new(reader).map_err(decode_error)?.into_frames(),
+            ImageFormat::Png => {
+                let decoder = PngDecoder::new(reader).map_err(decode_error)?;
+                if !decoder.is_apng().map_err(decode_error)? {
+                    return single(DynamicImage::from_decoder(decoder).map_err(decode_error)?);
+                }
+                decoder.apng().map_err(decode_error)?.into_frames()
+            }
+            ImageFormat::WebP => {
+                let decoder = WebPDecoder::new(reader).map_err(decode_error)?;
+                if !decoder.has_animation() {
+                    return single(DynamicImage::from_decoder(decoder).map_err(decode_error)?);
+                }
+                decoder.into_frames()
+            }
+            format => {
+                let image = ImageReader::with_format(reader, format);
+                return single(image.decode().map_err(decode_error)?);
+            }
+        };
+
+        let mut start = 0;
+        frames
+            .map(|frame| {
+                let frame = frame.map_err(decode_error)?;
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                let frame_start = start;
+                start += numer / denom.max(1);
+                Ok((frame_start, frame.into_buffer()))
+            })
+            .collect()
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to decode image: {e}")))?
+}
+
+/// Decode straight from the file, without buffering the encoded bytes first
+async fn load_image(file: File) -> Result<DynamicImage> {
+    let reader = BufReader::new(SyncIoBridge::new(file));
+    tokio::task::spawn_blocking(move || {
+        ImageReader::new(reader)
+            .with_guessed_format()?
+            .decode()
+            .map_err(ImageProviderError::DecodeError)
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to decode image: {e}")))?
+}
+
+/// Like [`load_image`], also returning the bytes the image was decoded from
+async fn load_image_buffered(mut file: File) -> Result<(Bytes, DynamicImage)> {
+    let mut source = vec![];
+    file.read_to_end(&mut source).await?;
+    let source = Bytes::from(source);
+    let image = tokio::task::spawn_blocking({
+        let source = source.clone();
+        move || image::load_from_memory(&source).map_err(ImageProviderError::DecodeError)
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to decode image: {e}")))??;
+    Ok((source, image))
+}
+
+/// Send the source file through the enhancement service and decode its answer
+#[cfg(feature = "enhance")]
+async fn load_enhanced(
+    enhancer: &Enhancer,
+    path: &PathBuf,
+    format: ImageFormat,
+    mode: &str,
+) -> Result<DynamicImage> {
+    let source = tokio::fs::read(path).await?;
+    let enhanced = enhancer
+        .enhance(source.into(), format.to_mime_type(), mode)
+        .await?;
+
+    tokio::task::spawn_blocking(move || {
+        image::load_from_memory(&enhanced).map_err(|e| {
+            ImageProviderError::Upstream(format!("Failed to decode enhanced image: {e}"))
+        })
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to decode image: {e}")))?
+}
+
+/// Decode a JPEG at 1/8 size, the scaled IDCT only reads the DC coefficient of each block
+#[cfg(feature = "mozjpeg")]
+async fn load_jpeg_dc(file: File) -> Result<DynamicImage> {
+    let reader = BufReader::new(SyncIoBridge::new(file));
+    tokio::task::spawn_blocking(move || {
+        let decode_error = |e| ImageProviderError::DecodeError(image::ImageError::IoError(e));
+
+        let mut decompress = mozjpeg::Decompress::new_reader(reader).map_err(decode_error)?;
+        decompress.scale(1);
+        let mut decompress = decompress.rgb().map_err(decode_error)?;
+        let (width, height) = (decompress.width() as u32, decompress.height() as u32);
+        let pixels = decompress.read_scanlines::<u8>().map_err(decode_error)?;
+        decompress.finish().map_err(decode_error)?;
+
+        Ok(DynamicImage::ImageRgb8(
+            image::RgbImage::from_raw(width, height, pixels).unwrap(),
+        ))
+    })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to decode image: {e}")))?
+}
+
+#[cfg(not(feature = "mozjpeg"))]
+async fn load_jpeg_dc(_file: File) -> Result<DynamicImage> {
+    Err(ImageProviderError::BadRequest(
+        "dc_only is unavailable, compile with --features mozjpeg".to_string(),
+    ))
+}
+
+#[cfg(feature = "svg")]
+fn parse_svg(source: &[u8]) -> Result<resvg::usvg::Tree> {
+    resvg::usvg::Tree::from_data(source, &resvg::usvg::Options::default())
+        .map_err(|e| ImageProviderError::BadRequest(format!("Invalid SVG: {e}")))
+}
+
+/// Size of an SVG in pixels, from its `width` and `height` or else its `viewBox`
+#[cfg(feature = "svg")]
+fn svg_size(source: &[u8]) -> Result<(u32, u32)> {
+    let size = parse_svg(source)?.size();
+    Ok((size.width().ceil() as u32, size.height().ceil() as u32))
+}
+
+#[cfg(not(feature = "svg"))]
+fn svg_size(_source: &[u8]) -> Result<(u32, u32)> {
+    Err(ImageProviderError::UnsupportedMediaType(
+        "SVG sources are unavailable, compile with --features svg".to_string(),
+    ))
+}
+
+/// Rasterize an SVG large enough to cover `w`x`h` (times `dpr`), or at its own size without
+/// either, so resizing afterwards never scales it up. Capped at the largest output size
+#[cfg(feature = "svg")]
+async fn load_svg(
+    mut file: File,
+    (width, height): (Option<u32>, Option<u32>),
+    dpr: f32,
+    config: &ResizeConfig,
+) -> Result<DynamicImage> {
+    let mut source = vec![];
+    file.read_to_end(&mut source).await?;
+    let (max_width, max_height) = (config.max_width as f32, config.max_height as f32);
+    tokio::task::spawn_blocking(move || {
+        let tree = parse_svg(&source)?;
+        let size = tree.size();
+        let scale = [
+            width.map(|width| width as f32 / size.width()),
+            height.map(|height| height as f32 / size.height()),
+        ]
+        .into_iter()
+        .flatten()
+        .reduce(f32::max)
+        .unwrap_or(1.0)
+            * dpr;
+        let scale = scale
+            .min(max_width / size.width())
+            .min(max_height / size.height());
+        let raster_width = ((size.width() * scale).round() as u32).max(1);
+        let raster_height = ((size.height() * scale).round() as u32).max(1);
+
+        let mut pixmap = resvg::tiny_skia::Pixmap::new(raster_width, raster_height)
+            .ok_or(ImageProviderError::BadRequest("SVG has no size".to_string()))?;
+        let transform = resvg::tiny_skia::Transform::from_scale(
+            raster_width as f32 / size.width(),
+            raster_height as f32 / size.height(),
+        );
+        resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+        // tiny-skia keeps premultiplied alpha, `image` expects it straight
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|pixel| {
+                let color = pixel.demultiply();
+                [color.red(), color.green(), color.blue(), color.alpha()]
+            })
+            .collect();
+        Ok(DynamicImage::ImageRgba8(
+            image::RgbaImage::from_raw(raster_width, raster_height, pixels).unwrap(),
+        ))
     })
+    .await
+    .map_err(|e| ImageProviderError::Internal(format!("Failed to rasterize SVG: {e}")))?
+}
+
+#[cfg(not(feature = "svg"))]
+async fn load_svg(
+    _file: File,
+    _size: (Option<u32>, Option<u32>),
+    _dpr: f32,
+    _config: &ResizeConfig,
+) -> Result<DynamicImage> {
+    Err(ImageProviderError::UnsupportedMediaType(
+        "SVG sources are unavailable, compile with --features svg".to_string(),
+    ))
+}
+
+/// Decode a secondary input (`diff`, `watermark`, `stitch`), rasterizing SVGs at their own size
+async fn load_source(path: &PathBuf, config: &ResizeConfig) -> Result<DynamicImage> {
+    let file = load_file(path, config).await?;
+    match is_svg(path) {
+        true => load_svg(file, (None, None), 1.0, config).await,
+        false => load_image(file).await,
+    }
 }
 
 fn resize_image(
     config: &ResizeConfig,
     src_image: &DynamicImage,
-    dst_image: &mut Image<'_>,
+    dst_image: &mut DynamicImage,
 ) -> Result<()> {
-    let mut resizer = Resizer::new();
+    resize_image_fit(config, src_image, dst_image, Fit::Cover(0.5, 0.5))
+}
 
+fn resize_image_fit(
+    config: &ResizeConfig,
+    src_image: &DynamicImage,
+    dst_image: &mut DynamicImage,
+    fit: Fit,
+) -> Result<()> {
     let algorithm = if cfg!(debug_assertions) {
         ResizeAlg::Nearest
     } else {
         config.resize_algorithm()
     };
-    let options = ResizeOptions::new()
-        .resize_alg(algorithm)
-        .fit_into_destination(Some((0.5, 0.5)));
+    resize_image_with(algorithm, src_image, dst_image, fit.centering())
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "resize_image",
+        level = "debug",
+        skip_all,
+        fields(
+            src = ?(src_image.width(), src_image.height()),
+            dst = ?(dst_image.width(), dst_image.height()),
+        )
+    )
+)]
+fn resize_image_with(
+    algorithm: ResizeAlg,
+    src_image: &DynamicImage,
+    dst_image: &mut DynamicImage,
+    centering: Option<(f64, f64)>,
+) -> Result<()> {
+    let mut resizer = Resizer::new();
+    let mut options = ResizeOptions::new().resize_alg(algorithm);
+    if centering.is_some() {
+        options = options.fit_into_destination(centering);
+    }
 
     resizer
         .resize(src_image, dst_image, Some(&options))
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to resize image".to_string(),
-            )
-        })
+        .map_err(ImageProviderError::ResizeError)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EncodeOptions {
+    quality: Option<u8>,
+    webp_method: u8,
+    webp_exact: bool,
+    webp_alpha_quality: Option<u8>,
+    webp_segments: u8,
+    avif_speed: u8,
+    /// Tile columns and rows, rav1e picks them from the image size without
+    avif_tiles: Option<(usize, usize)>,
+    /// Used when `quality` is not set
+    avif_quality: u8,
+    /// Used when `quality` is not set
+    jpeg_quality: u8,
+    /// Used when `quality` is not set, lossless without either
+    webp_quality: Option<u8>,
+    png_compression: PngCompression,
+    png_filter: PngFilter,
+    #[cfg_attr(not(feature = "mozjpeg"), allow(dead_code))]
+    jpeg_huffman_optimize: bool,
+    #[cfg_attr(not(feature = "jpeg2000"), allow(dead_code))]
+    j2k_ratio: Option<u32>,
+    /// The image is already quantized, so PNG can be written with a palette
+    #[cfg_attr(not(feature = "pngquant"), allow(dead_code))]
+    indexed_png: bool,
+    /// Composited behind transparency for formats without alpha
+    background: [u8; 3],
+}
+
+/// Same as a request without any encoding parameters
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            quality: None,
+            webp_method: 4,
+            webp_exact: false,
+            webp_alpha_quality: None,
+            webp_segments: 4,
+            avif_speed: 4,
+            avif_tiles: None,
+            avif_quality: DEFAULT_AVIF_QUALITY,
+            jpeg_quality: DEFAULT_QUALITY,
+            webp_quality: None,
+            png_compression: PngCompression::Fast,
+            png_filter: PngFilter::Adaptive,
+            jpeg_huffman_optimize: false,
+            j2k_ratio: None,
+            indexed_png: false,
+            background: [255, 255, 255],
+        }
+    }
 }
 
-fn encode_image(format: ImageFormat, image: &Image<'_>, color: ColorType) -> Result<Bytes> {
+/// Quality for lossy encoders when `q` is omitted
+pub const DEFAULT_QUALITY: u8 = 80;
+/// AVIF holds up at lower quality settings, so it gets its own default
+pub const DEFAULT_AVIF_QUALITY: u8 = 60;
+/// AVIF encoders compiled in, for `avif_codec`. rav1e is the one the `image` crate ships
+pub const AVIF_CODECS: [&str; 1] = ["rav1e"];
+
+/// Encode `image` the way the server does for a request without encoding parameters.
+///
+/// `quality` (`1` to `100`) applies to JPEG, AVIF and WebP, `0` or anything above `100` is
+/// [`ImageProviderError::BadRequest`] like `q` in a request. Without it JPEG uses
+/// [`DEFAULT_QUALITY`], AVIF uses [`DEFAULT_AVIF_QUALITY`] and WebP is lossless. JPEG output
+/// is composited onto white where `image` is transparent.
+///
+/// Supports WebP, PNG, JPEG, AVIF, TIFF and GIF, anything else is
+/// [`ImageProviderError::UnsupportedFormat`].
+///
+/// ```
+/// use image::{DynamicImage, ImageFormat, RgbImage};
+/// use image_provider::encode_image;
+///
+/// let image = DynamicImage::ImageRgb8(RgbImage::new(64, 48));
+/// let jpeg = encode_image(ImageFormat::Jpeg, &image, Some(90)).unwrap();
+/// assert_eq!(image::guess_format(&jpeg).unwrap(), ImageFormat::Jpeg);
+///
+/// let decoded = image::load_from_memory(&encode_image(ImageFormat::WebP, &image, None).unwrap());
+/// assert_eq!(decoded.unwrap(), image); // Lossless
+///
+/// assert!(encode_image(ImageFormat::Jpeg, &image, Some(0)).is_err());
+/// ```
+pub fn encode_image(
+    format: ImageFormat,
+    image: &DynamicImage,
+    quality: Option<u8>,
+) -> Result<Bytes> {
+    if let Some(q) = quality.filter(|q| !(1..=100).contains(q)) {
+        return Err(ImageQueryError::Quality(q).into());
+    }
+    let options = EncodeOptions {
+        quality,
+        ..Default::default()
+    };
+    encode_with_options(format, image, &options)
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "encode_image", level = "debug", skip_all, fields(?format))
+)]
+fn encode_with_options(
+    format: ImageFormat,
+    image: &DynamicImage,
+    options: &EncodeOptions,
+) -> Result<Bytes> {
+    // JPEG has no alpha channel, blend transparency into the background instead
+    let flattened;
+    let image = match format {
+        ImageFormat::Jpeg if image.color().has_alpha() => {
+            flattened = DynamicImage::ImageRgb8(flatten_alpha(image, options.background));
+            &flattened
+        }
+        _ => image,
+    };
+
     macro_rules! match_format {
         ($format: expr , $( $target: pat => $encoder: expr, )+ ) => {
             match $format {$(
                 $target => $encoder
-                    .write_image(image.buffer(), image.width(), image.height(), color.into())
-                    .map_err(|e| {
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            format!("Failed to encode image: {e}"),
-                        )
-                    }),
+                    .write_image(image.as_bytes(), image.width(), image.height(), image.color().into())
+                    .map_err(ImageProviderError::EncodeError),
             )+
-                _ => Err((StatusCode::BAD_REQUEST, "Unsupported output format".to_string())),
+                format => Err(ImageProviderError::UnsupportedFormat(format.to_mime_type().to_string())),
             }
         };
     }
 
     let mut bytes = vec![];
+    #[cfg(feature = "pngquant")]
+    if format == ImageFormat::Png && options.indexed_png {
+        let image = image.to_rgba8();
+        encoder::IndexedPngEncoder::new(&mut bytes)
+            .write_image(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                image::ExtendedColorType::Rgba8,
+            )
+            .map_err(ImageProviderError::EncodeError)?;
+        return Ok(Bytes::from(bytes));
+    }
+
+    // The GIF encoder only takes RGB(A), whatever the image was converted to
+    if format == ImageFormat::Gif {
+        let image = image.to_rgba8();
+        GifEncoder::new(&mut bytes)
+            .write_image(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                image::ExtendedColorType::Rgba8,
+            )
+            .map_err(ImageProviderError::EncodeError)?;
+        return Ok(Bytes::from(bytes));
+    }
+
+    if let (ImageFormat::Avif, Some((cols, rows))) = (format, options.avif_tiles) {
+        let image = image.to_rgba8();
+        let quality = options.quality.unwrap_or(options.avif_quality);
+        encoder::TiledAvifEncoder::new_with_speed_quality(&mut bytes, options.avif_speed, quality)
+            .with_tiles(cols, rows)
+            .write_image(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                image::ExtendedColorType::Rgba8,
+            )
+            .map_err(ImageProviderError::EncodeError)?;
+        return Ok(Bytes::from(bytes));
+    }
+
+    #[cfg(feature = "mozjpeg")]
+    if format == ImageFormat::Jpeg && options.jpeg_huffman_optimize {
+        let quality = options.quality.unwrap_or(options.jpeg_quality);
+        encoder::MozJpegEncoder::new_with_quality(&mut bytes, quality)
+            .write_image(
+                image.as_bytes(),
+                image.width(),
+                image.height(),
+                image.color().into(),
+            )
+            .map_err(ImageProviderError::EncodeError)?;
+        return Ok(Bytes::from(bytes));
+    }
+
+    let webp_quality = options.quality.or(options.webp_quality);
+    let jpeg_quality = options.quality.unwrap_or(options.jpeg_quality);
     match_format! {
         format,
-        ImageFormat::WebP => WebPEncoder::new_lossless(&mut bytes),
-        ImageFormat::Png => PngEncoder::new(&mut bytes),
-        ImageFormat::Jpeg => JpegEncoder::new(&mut bytes),
+        ImageFormat::WebP => match (webp_quality, options.webp_alpha_quality) {
+            (None, None) => WebPEncoder::new_lossless(&mut bytes),
+            (quality, _) => {
+                WebPEncoder::new_lossy(&mut bytes, quality.unwrap_or(DEFAULT_QUALITY).into())
+            }
+        }
+            .with_alpha_quality(options.webp_alpha_quality)
+            .with_segments(options.webp_segments)
+            .with_method(options.webp_method)
+            .with_exact(options.webp_exact),
+        ImageFormat::Png => PngEncoder::new_with_quality(
+            &mut bytes,
+            options.png_compression.into(),
+            options.png_filter.into(),
+        ),
+        ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut bytes, jpeg_quality),
+        ImageFormat::Avif => AvifEncoder::new_with_speed_quality(
+            &mut bytes,
+            options.avif_speed,
+            options.quality.unwrap_or(options.avif_quality),
+        ),
+        // TIFF seeks back to write its directory offsets
+        ImageFormat::Tiff => TiffEncoder::new(Cursor::new(&mut bytes)),
     }?;
 
     Ok(Bytes::from(bytes))
 }
+
+#[cfg(feature = "jpeg2000")]
+fn encode_jpeg2000(image: &DynamicImage, options: &EncodeOptions) -> Result<Bytes> {
+    let mut bytes = vec![];
+    encoder::Jpeg2000Encoder::new_lossless(&mut bytes)
+        .with_ratio(options.j2k_ratio.map(|ratio| ratio as f32))
+        .write_image(
+            image.as_bytes(),
+            image.width(),
+            image.height(),
+            image.color().into(),
+        )
+        .map_err(ImageProviderError::EncodeError)?;
+
+    Ok(Bytes::from(bytes))
+}
+
+#[cfg(not(feature = "jpeg2000"))]
+fn encode_jpeg2000(_image: &DynamicImage, _options: &EncodeOptions) -> Result<Bytes> {
+    Err(ImageProviderError::BadRequest(
+        "JPEG 2000 output is unavailable, compile with --features jpeg2000".to_string(),
+    ))
+}
+
+fn encode_raw(image: &DynamicImage, format: PixelFormat) -> Bytes {
+    Bytes::from(match format {
+        PixelFormat::Rgba8 => image.to_rgba8().into_raw(),
+        PixelFormat::Rgb8 => image.to_rgb8().into_raw(),
+        PixelFormat::Gray8 => image.to_luma8().into_raw(),
+    })
+}
+
+fn encode_lqip(image: &DynamicImage, background: [u8; 3]) -> Result<Bytes> {
+    const LQIP_QUALITY: u8 = 20;
+
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, LQIP_QUALITY)
+        .encode_image(&flatten_alpha(image, background))
+        .map_err(ImageProviderError::EncodeError)?;
+    let url = format!("data:image/jpeg;base64,{}", BASE64_STANDARD.encode(jpeg));
+    Ok(Bytes::from(url))
+}
+
+/// Blend the image over a solid `background`, dropping its alpha channel
+fn flatten_alpha(image: &DynamicImage, background: [u8; 3]) -> image::RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+
+    let image = image.to_rgba8();
+    image::RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let blend = |color: u8, background: u8| {
+            let (color, background, a) = (color as u32, background as u32, a as u32);
+            ((color * a + background * (255 - a) + 127) / 255) as u8
+        };
+        image::Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    })
+}
+
+/// `rgb`/`rrggbb` hex with an optional `#`, or a basic CSS color name
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let named = match color.to_ascii_lowercase().as_str() {
+        "white" => Some([255, 255, 255]),
+        "black" => Some([0, 0, 0]),
+        "gray" | "grey" => Some([128, 128, 128]),
+        "silver" => Some([192, 192, 192]),
+        "red" => Some([255, 0, 0]),
+        "maroon" => Some([128, 0, 0]),
+        "orange" => Some([255, 165, 0]),
+        "yellow" => Some([255, 255, 0]),
+        "olive" => Some([128, 128, 0]),
+        "lime" => Some([0, 255, 0]),
+        "green" => Some([0, 128, 0]),
+        "aqua" | "cyan" => Some([0, 255, 255]),
+        "teal" => Some([0, 128, 128]),
+        "blue" => Some([0, 0, 255]),
+        "navy" => Some([0, 0, 128]),
+        "fuchsia" | "magenta" => Some([255, 0, 255]),
+        "purple" => Some([128, 0, 128]),
+        _ => None,
+    };
+    if named.is_some() {
+        return named;
+    }
+
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    match hex.len() {
+        // Each digit is doubled, `f80` is `ff8800`
+        3 => Some([
+            channel(&hex[0..1])? * 17,
+            channel(&hex[1..2])? * 17,
+            channel(&hex[2..3])? * 17,
+        ]),
+        6 => Some([
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        ]),
+        _ => None,
+    }
+}
+
+fn encode_ascii(image: &DynamicImage, ramp: &str) -> Result<Bytes> {
+    let ramp: Vec<char> = ramp.chars().collect();
+    if ramp.is_empty() {
+        return Err(ImageProviderError::Internal(
+            "Ascii ramp is empty".to_string(),
+        ));
+    }
+
+    // Ascii art is rendered from luma only, one character per pixel
+    let image = image.to_luma8();
+    let width = image.width() as usize;
+    let mut text = String::with_capacity((width + 1) * image.height() as usize);
+    for row in image.as_raw().chunks_exact(width) {
+        for &luma in row {
+            let index = luma as usize * (ramp.len() - 1) / u8::MAX as usize;
+            text.push(ramp[index]);
+        }
+        text.push('\n');
+    }
+
+    Ok(Bytes::from(text))
+}
@@ -1,61 +1,198 @@
-use clap::Parser;
-use clap_verbosity_flag::{InfoLevel, Verbosity};
-use console::style;
+use clap::{Parser, ValueEnum};
+use console::{style, Style};
 use local_ip_address::local_ip;
 use log::info;
 use qrcode::{render::unicode, QrCode};
-use std::{net::SocketAddr, path::PathBuf};
-use tower::ServiceBuilder;
-use tower_http::cors::{Any, CorsLayer};
+use std::{
+    fmt,
+    io::{self, IsTerminal},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
 
 use image_provider::{get_images_router, ResizeConfig};
 
 #[derive(Debug, Clone, Parser)]
 pub struct Config {
-    #[clap(default_value = ".")]
-    path: PathBuf,
+    /// Same as `--root`, which it overrides
+    path: Option<PathBuf>,
     #[clap(long, short, default_value = "3000")]
     port: u16,
+    /// Address to listen on, e.g. `127.0.0.1` for local only or `::` for IPv6
+    #[clap(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    bind: IpAddr,
     #[clap(flatten)]
     resize: ResizeConfig,
-    #[command(flatten)]
-    verbose: Verbosity<InfoLevel>,
+    /// Log output format
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Maximum log level
+    #[clap(long, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+    /// Print this URL (e.g. `/photo.jpg?w=800`) signed with `--resize-images-secret` and exit
+    #[clap(long, value_name = "URL", requires = "resize-images-secret")]
+    sign: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Pretty,
+    #[cfg(feature = "log-json")]
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let config = Config::parse();
-    env_logger::builder()
-        .filter_level(config.verbose.log_level_filter())
-        .format_target(false)
-        .init();
-
-    let images_router = get_images_router(config.path, config.resize);
+    if let (Some(url), Some(secret)) = (&config.sign, &config.resize.secret) {
+        println!("{}", image_provider::sign_url(secret, url));
+        return;
+    }
+    init_logger(config.log_format, config.log_level);
 
-    let app = images_router.layer(ServiceBuilder::new().layer(CorsLayer::new().allow_origin(Any)));
+    let mut resize = config.resize;
+    if let Some(path) = config.path {
+        resize.root = path;
+    }
+    let app = get_images_router(resize);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+    let addr = SocketAddr::new(config.bind, config.port);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
-    show_urls(config.port);
+    show_urls(addr);
 
     info!(
         "Press {} to stop the server",
         style("Ctrl + C").green().bold()
     );
 
-    axum::serve(listener, app).await.unwrap();
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+    info!("Shutdown complete");
 }
 
-pub fn show_urls(port: u16) {
-    info!(
-        " {} http://localhost:{} ",
-        style("Local").green().bold(),
-        port
-    );
+/// Resolves on Ctrl + C or SIGTERM, then in-flight requests are drained before exiting.
+/// The disk cache is write-through, so there is no cache to flush afterwards.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl + C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+    info!("Shutting down gracefully…");
+}
+
+fn init_logger(format: LogFormat, level: LogLevel) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(level))
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => builder.event_format(TextFormat).init(),
+        LogFormat::Pretty => builder.pretty().init(),
+        #[cfg(feature = "log-json")]
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Mirrors the `[timestamp LEVEL] message` lines of the previous `env_logger` output
+struct TextFormat;
+
+impl<S, N> FormatEvent<S, N> for TextFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let level = *event.metadata().level();
+        let style = match level {
+            Level::ERROR => Style::new().red(),
+            Level::WARN => Style::new().yellow(),
+            Level::INFO => Style::new().green(),
+            Level::DEBUG => Style::new().blue(),
+            Level::TRACE => Style::new().cyan(),
+        };
+        let style = style.force_styling(writer.has_ansi_escapes());
+
+        write!(
+            writer,
+            "[{} {}] ",
+            jiff::Timestamp::now().strftime("%Y-%m-%dT%H:%M:%SZ"),
+            style.apply_to(format!("{level:<5}"))
+        )?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+pub fn show_urls(addr: SocketAddr) {
+    let (ip, port) = (addr.ip(), addr.port());
+    if ip.is_unspecified() || ip.is_loopback() {
+        info!(
+            " {} http://localhost:{} ",
+            style("Local").green().bold(),
+            port
+        );
+    }
 
-    if let Ok(addr) = local_ip() {
-        let url = format!("http://{addr}:{port}");
+    let network = match ip {
+        ip if ip.is_unspecified() => local_ip().ok(),
+        ip if ip.is_loopback() => None,
+        ip => Some(ip),
+    };
+    if let Some(ip) = network {
+        let url = format!("http://{}", SocketAddr::new(ip, port));
         info!(" {} {}", style("Network").green().bold(), url);
 
         let qrcode = QrCode::new(url.clone()).unwrap();
@@ -0,0 +1,53 @@
+use image::{GrayImage, RgbImage};
+
+/// Peak signal-to-noise ratio in dB, `inf` for identical images
+pub fn psnr(reference: &RgbImage, image: &RgbImage) -> f64 {
+    let squared_error: f64 = reference
+        .as_raw()
+        .iter()
+        .zip(image.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mse = squared_error / reference.as_raw().len() as f64;
+    10.0 * (255.0_f64.powi(2) / mse).log10()
+}
+
+/// Mean SSIM over non-overlapping 8x8 blocks, edge blocks are clipped
+pub fn ssim(reference: &GrayImage, image: &GrayImage) -> f64 {
+    const BLOCK: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = reference.dimensions();
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for by in (0..height).step_by(BLOCK as usize) {
+        for bx in (0..width).step_by(BLOCK as usize) {
+            let pixels: Vec<(f64, f64)> = (by..(by + BLOCK).min(height))
+                .flat_map(|y| (bx..(bx + BLOCK).min(width)).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    (
+                        reference.get_pixel(x, y).0[0] as f64,
+                        image.get_pixel(x, y).0[0] as f64,
+                    )
+                })
+                .collect();
+            let n = pixels.len() as f64;
+
+            let mean_a = pixels.iter().map(|(a, _)| a).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|(_, b)| b).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covar) = (0.0, 0.0, 0.0);
+            for (a, b) in &pixels {
+                var_a += (a - mean_a).powi(2);
+                var_b += (b - mean_b).powi(2);
+                covar += (a - mean_a) * (b - mean_b);
+            }
+            let (var_a, var_b, covar) = (var_a / n, var_b / n, covar / n);
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+            blocks += 1;
+        }
+    }
+    total / blocks as f64
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Upper bounds of the processing duration histogram, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Request counters served at `/metrics` in the Prometheus text format
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    processing_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "image_provider_requests_total",
+                "Image requests by response status.",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        let cache_hits = IntCounter::new(
+            "image_provider_cache_hits_total",
+            "Images served from the cache.",
+        )
+        .unwrap();
+        let cache_misses = IntCounter::new(
+            "image_provider_cache_misses_total",
+            "Images processed because they were not cached.",
+        )
+        .unwrap();
+        let processing_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "image_provider_processing_duration_seconds",
+                "Time spent resizing and encoding an image.",
+            )
+            .buckets(DURATION_BUCKETS.to_vec()),
+        )
+        .unwrap();
+
+        // Registering only fails on clashing names, which are fixed above
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry
+            .register(Box::new(processing_duration.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            cache_hits,
+            cache_misses,
+            processing_duration,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, status: u16) {
+        self.requests.with_label_values(&[status.to_string()]).inc();
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.inc();
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.inc();
+    }
+
+    pub fn record_processing(&self, duration: Duration) {
+        self.processing_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = vec![];
+        // Encoding only fails for a writer that does
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut out);
+        String::from_utf8(out).unwrap_or_default()
+    }
+}
@@ -0,0 +1,108 @@
+use std::{fs, io::Cursor};
+
+use image::{codecs::webp::WebPEncoder, ImageEncoder, RgbaImage};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+
+fn chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = name.to_vec();
+    chunk.extend((data.len() as u32).to_le_bytes());
+    chunk.extend(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+/// Chunks after `WEBP` of a lossless still image
+fn still_chunks(color: [u8; 4]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    WebPEncoder::new_lossless(Cursor::new(&mut bytes))
+        .write_image(
+            &RgbaImage::from_pixel(16, 16, image::Rgba(color)),
+            16,
+            16,
+            image::ExtendedColorType::Rgba8,
+        )
+        .unwrap();
+    bytes.split_off(12)
+}
+
+/// Two 16x16 frames, red then blue, 100ms each
+fn animated_webp() -> Vec<u8> {
+    let mut vp8x = vec![0x02 | 0x10, 0, 0, 0]; // Animation and alpha
+    vp8x.extend(u24(15));
+    vp8x.extend(u24(15));
+
+    let mut body = b"WEBP".to_vec();
+    body.extend(chunk(b"VP8X", &vp8x));
+    body.extend(chunk(b"ANIM", &[0, 0, 0, 0, 0, 0]));
+    for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+        let mut frame = [u24(0), u24(0), u24(15), u24(15), u24(100)].concat();
+        frame.push(0);
+        frame.extend(still_chunks(color));
+        body.extend(chunk(b"ANMF", &frame));
+    }
+
+    let mut riff = b"RIFF".to_vec();
+    riff.extend((body.len() as u32).to_le_bytes());
+    riff.extend(body);
+    riff
+}
+
+#[tokio::test]
+async fn serves_animated_webp_untouched_and_rejects_resizing() {
+    let root = std::env::temp_dir().join(format!("image-provider-anim-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let animated = animated_webp();
+    fs::write(root.join("anim.webp"), &animated).unwrap();
+    let mut still = b"RIFF".to_vec();
+    let chunks = [b"WEBP".to_vec(), still_chunks([0, 255, 0, 255])].concat();
+    still.extend((chunks.len() as u32).to_le_bytes());
+    still.extend(chunks);
+    fs::write(root.join("still.webp"), &still).unwrap();
+
+    let config = ResizeConfigBuilder::default()
+        .root(root.clone())
+        .build()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, get_images_router(config)).await });
+
+    let get = |query: &'static str| {
+        let url = format!("{url}/{query}");
+        async move {
+            let response = reqwest::Client::new()
+                .get(url)
+                .header("accept", "image/webp")
+                .send()
+                .await
+                .unwrap();
+            let status = response.status().as_u16();
+            (status, response.bytes().await.unwrap().to_vec())
+        }
+    };
+
+    assert_eq!(get("anim.webp").await, (200, animated));
+    assert_eq!(get("anim.webp?w=8").await.0, 415);
+    assert_eq!(get("anim.webp?output=png").await.0, 415);
+    assert_eq!(get("still.webp?w=8").await.0, 200);
+
+    // Strips decode every frame themselves
+    let (status, strip) = get("anim.webp?output=thumbnail_strip&count=2&w=8").await;
+    assert_eq!(status, 200);
+    let strip = image::load_from_memory(&strip).unwrap().to_rgb8();
+    assert_eq!(strip.dimensions(), (16, 8));
+    let [r, _, b] = strip.get_pixel(4, 4).0;
+    assert!(r > 200 && b < 50, "first frame is red");
+    let [r, _, b] = strip.get_pixel(12, 4).0;
+    assert!(r < 50 && b > 200, "second frame is blue");
+
+    let _ = fs::remove_dir_all(&root);
+}
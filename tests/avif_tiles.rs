@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+    Router,
+};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+use tower::ServiceExt;
+
+fn router() -> Router {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = ResizeConfigBuilder::default().root(root).build().unwrap();
+    get_images_router(config)
+}
+
+async fn get(router: &Router, uri: &str) -> (StatusCode, Vec<u8>) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, body.to_vec())
+}
+
+/// Reads the AV1 bitstream MSB first
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, count: usize) -> usize {
+        (0..count).fold(0, |value, _| {
+            let bit = self.data[self.position / 8] >> (7 - self.position % 8) & 1;
+            self.position += 1;
+            value << 1 | bit as usize
+        })
+    }
+
+    fn flag(&mut self) -> bool {
+        self.read(1) == 1
+    }
+}
+
+/// Smallest `k` with `block << k >= target`
+fn tile_log2(block: usize, target: usize) -> usize {
+    (0..).find(|k| block << k >= target).unwrap()
+}
+
+/// Tile columns and rows of the color image in an AVIF written by rav1e: a still picture with
+/// a reduced header and uniformly spaced tiles
+fn tile_layout(avif: &[u8]) -> (usize, usize) {
+    // The AV1 data is all of `mdat`, there is no alpha to follow it
+    let mut boxes = avif;
+    let mut av1 = loop {
+        let size = u32::from_be_bytes(boxes[..4].try_into().unwrap()) as usize;
+        if &boxes[4..8] == b"mdat" {
+            break &boxes[8..size];
+        }
+        boxes = &boxes[size..];
+    };
+
+    let (mut sequence, mut frame) = (None, None);
+    while !av1.is_empty() {
+        let header = av1[0];
+        assert_eq!(header & 0b110, 0b010, "OBUs have a size and no extension");
+        let (mut size, mut length) = (0, 1);
+        loop {
+            let byte = av1[length];
+            size |= ((byte & 0x7f) as usize) << (7 * (length - 1));
+            length += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let payload = &av1[length..length + size];
+        match header >> 3 & 0xf {
+            1 => sequence = Some(payload),
+            3 | 6 => frame = Some(payload),
+            _ => {}
+        }
+        av1 = &av1[length + size..];
+    }
+
+    let mut bits = Bits {
+        data: sequence.unwrap(),
+        position: 0,
+    };
+    bits.read(3); // seq_profile
+    assert!(bits.flag(), "still_picture");
+    assert!(bits.flag(), "reduced_still_picture_header");
+    bits.read(5); // seq_level_idx
+    let width_bits = bits.read(4) + 1;
+    let height_bits = bits.read(4) + 1;
+    let width = bits.read(width_bits) + 1;
+    let height = bits.read(height_bits) + 1;
+    let superblock_log2 = match bits.flag() {
+        true => 7,
+        false => 6,
+    };
+    bits.read(2); // enable_filter_intra, enable_intra_edge_filter
+    let superres = bits.flag();
+
+    // A reduced header starts the frame header at disable_cdf_update
+    let mut bits = Bits {
+        data: frame.unwrap(),
+        position: 0,
+    };
+    bits.read(1); // disable_cdf_update
+    let screen_content = bits.flag();
+    if screen_content {
+        bits.read(1); // force_integer_mv
+    }
+    if superres {
+        assert!(!bits.flag(), "use_superres");
+    }
+    if bits.flag() {
+        bits.read(32); // render_width_minus_1, render_height_minus_1
+    }
+    if screen_content {
+        bits.read(1); // allow_intrabc
+    }
+
+    // tile_info(), see https://aomediacodec.github.io/av1-spec/#tile-info-syntax
+    let superblocks = |pixels: usize| (pixels.div_ceil(8) * 8).div_ceil(1 << superblock_log2);
+    let (sb_cols, sb_rows) = (superblocks(width), superblocks(height));
+    let min_cols_log2 = tile_log2(4096 >> superblock_log2, sb_cols);
+    let max_cols_log2 = tile_log2(1, sb_cols.min(64));
+    let max_rows_log2 = tile_log2(1, sb_rows.min(64));
+    let min_log2 = min_cols_log2.max(tile_log2(
+        (4096 * 2304) >> (2 * superblock_log2),
+        sb_cols * sb_rows,
+    ));
+    assert!(bits.flag(), "uniform_tile_spacing_flag");
+    let mut cols_log2 = min_cols_log2;
+    while cols_log2 < max_cols_log2 && bits.flag() {
+        cols_log2 += 1;
+    }
+    let mut rows_log2 = min_log2.saturating_sub(cols_log2);
+    while rows_log2 < max_rows_log2 && bits.flag() {
+        rows_log2 += 1;
+    }
+    let tile_width = sb_cols.div_ceil(1 << cols_log2);
+    let tile_height = sb_rows.div_ceil(1 << rows_log2);
+    (sb_cols.div_ceil(tile_width), sb_rows.div_ceil(tile_height))
+}
+
+#[tokio::test]
+async fn avif_tiles_set_the_tile_layout() {
+    let router = router();
+    // 256x192, so 4x3 superblocks of 64 pixels
+    let uri =
+        |tiles: &str| format!("/photo.jpg?w=256&output=avif&avif_speed=10&avif_tiles={tiles}");
+    for (tiles, layout) in [
+        ("1x1", (1, 1)),
+        ("2x2", (2, 2)),
+        ("1x2", (1, 2)),
+        // Rounded up to powers of two
+        ("3x1", (4, 1)),
+        // Clamped to a tile per superblock
+        ("64x64", (4, 3)),
+    ] {
+        let (status, avif) = get(&router, &uri(tiles)).await;
+        assert_eq!(status, StatusCode::OK, "{tiles}");
+        assert_eq!(tile_layout(&avif), layout, "{tiles}");
+    }
+
+    for tiles in ["2", "2x", "0x2", "65x1", "axb"] {
+        let (status, _) = get(&router, &uri(tiles)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{tiles}");
+    }
+}
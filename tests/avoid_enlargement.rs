@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{
+        header::{CONTENT_TYPE, ETAG, RANGE},
+        Request, StatusCode,
+    },
+    Router,
+};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+use tower::ServiceExt;
+
+fn fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn router(avoid_enlargement: bool) -> Router {
+    let config = ResizeConfigBuilder::default()
+        .root(fixtures())
+        .avoid_enlargement(avoid_enlargement)
+        .build()
+        .unwrap();
+    get_images_router(config)
+}
+
+async fn get(router: &Router, uri: &str) -> (String, Vec<u8>) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let content_type = response.headers()[CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (content_type, body.to_vec())
+}
+
+#[tokio::test]
+async fn larger_output_falls_back_to_original() {
+    let original = std::fs::read(fixtures().join("photo.jpg")).unwrap();
+
+    // A photo re-encoded as PNG is far larger than the JPEG
+    let (content_type, body) = get(&router(false), "/photo.jpg?output=png").await;
+    assert_eq!(content_type, "image/png");
+    assert!(body.len() > original.len());
+
+    let router = router(true);
+    for _ in 0..2 {
+        // The second request is served from the cache
+        let (content_type, body) = get(&router, "/photo.jpg?output=png").await;
+        assert_eq!(content_type, "image/jpeg");
+        assert_eq!(body, original);
+    }
+
+    // The fallback is served from the buffered source like any processed output
+    let request = Request::get("/photo.jpg?output=png")
+        .header(RANGE, "bytes=0-9")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert!(response.headers().contains_key(ETAG));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original[..10]);
+
+    let (content_type, body) = get(&router, "/photo.jpg?w=40").await;
+    assert_eq!(content_type, "image/jpeg");
+    assert!(body.len() < original.len());
+
+    // Transforms are never dropped
+    let (content_type, _) = get(&router, "/photo.jpg?output=png&gray=1").await;
+    assert_eq!(content_type, "image/png");
+}
@@ -0,0 +1,53 @@
+use clap::Parser;
+use image_provider::{ResizeConfig, ResizeConfigBuilder};
+
+#[test]
+fn builder_defaults_match_cli_defaults() {
+    let built = ResizeConfigBuilder::default().build().unwrap();
+    let parsed = ResizeConfig::parse_from(["image-provider"]);
+    assert_eq!(format!("{built:?}"), format!("{parsed:?}"));
+}
+
+#[test]
+fn invalid_cors_settings_fail_to_build() {
+    let origins = ResizeConfigBuilder::default()
+        .cors_origins(Some(vec!["https://a.example\n".into()]))
+        .build();
+    assert!(origins.unwrap_err().contains("CORS origin"));
+
+    let methods = ResizeConfigBuilder::default()
+        .cors_allow_methods(vec!["GE T".into()])
+        .build();
+    assert!(methods.unwrap_err().contains("CORS method"));
+
+    let parsed = ResizeConfig::try_parse_from(["image-provider", "--cors-origins", "a\nb"]);
+    assert!(parsed.is_err());
+}
+
+#[test]
+fn precompress_requires_a_cache_dir() {
+    let built = ResizeConfigBuilder::default().precompress(true).build();
+    assert_eq!(built.unwrap_err(), "precompress requires cache_dir");
+
+    let parsed = ResizeConfig::try_parse_from(["image-provider", "--resize-images-precompress"]);
+    assert!(parsed.is_err());
+    let parsed = ResizeConfig::try_parse_from([
+        "image-provider",
+        "--resize-images-precompress",
+        "--resize-images-cache-dir",
+        "cache",
+    ]);
+    assert!(parsed.unwrap().precompress);
+}
+
+#[test]
+fn upload_output_must_be_encodable() {
+    let built = ResizeConfigBuilder::default()
+        .upload_output(Some("bmp".into()))
+        .build();
+    assert!(built.unwrap_err().contains("upload output"));
+    let built = ResizeConfigBuilder::default()
+        .upload_output(Some("webp".into()))
+        .build();
+    assert!(built.is_ok());
+}
@@ -0,0 +1,85 @@
+use std::{fs, io::Cursor, path::Path};
+
+use image::{ImageFormat, RgbImage};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+
+fn png_bytes() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 128]))
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+/// CRC-32 of a PNG chunk type and data
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A valid PNG whose header claims far more pixels than the decoder allows
+fn oversized_png() -> Vec<u8> {
+    let mut bytes = png_bytes();
+    // Width and height follow the signature, the IHDR length and type
+    bytes[16..20].copy_from_slice(&60_000u32.to_be_bytes());
+    bytes[20..24].copy_from_slice(&60_000u32.to_be_bytes());
+    let crc = crc32(&bytes[12..29]);
+    bytes[29..33].copy_from_slice(&crc.to_be_bytes());
+    bytes
+}
+
+async fn status(url: &str, name: &str) -> u16 {
+    let response = reqwest::get(format!("{url}/{name}?w=10")).await.unwrap();
+    response.status().as_u16()
+}
+
+fn write(root: &Path, name: &str, bytes: &[u8]) {
+    fs::write(root.join(name), bytes).unwrap();
+}
+
+#[tokio::test]
+async fn maps_decode_failures_to_status_codes() {
+    let root = std::env::temp_dir().join(format!("image-provider-decode-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let png = png_bytes();
+    write(&root, "valid.png", &png);
+    write(&root, "truncated.png", &png[..png.len() / 2]);
+    let mut corrupt = png.clone();
+    let data = corrupt
+        .windows(4)
+        .position(|window| window == b"IDAT")
+        .unwrap()
+        + 4;
+    corrupt[data..data + 32]
+        .iter_mut()
+        .for_each(|byte| *byte ^= 0x5a);
+    write(&root, "corrupt.png", &corrupt);
+    write(&root, "oversized.png", &oversized_png());
+
+    let config = ResizeConfigBuilder::default()
+        .root(root.clone())
+        .build()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, get_images_router(config)).await });
+
+    assert_eq!(status(&url, "valid.png").await, 200);
+    assert_eq!(status(&url, "truncated.png").await, 400);
+    assert_eq!(status(&url, "corrupt.png").await, 400);
+    assert_eq!(status(&url, "oversized.png").await, 413);
+
+    let _ = fs::remove_dir_all(&root);
+}
@@ -0,0 +1,143 @@
+use std::{fs, path::Path, time::Duration};
+
+use image::{ImageFormat, RgbaImage};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+
+fn config(root: &Path, cache_dir: &Path) -> ResizeConfigBuilder {
+    ResizeConfigBuilder::default()
+        .root(root.to_path_buf())
+        .cache_dir(Some(cache_dir.to_path_buf()))
+}
+
+/// Serve the images router with a fresh in-memory cache, returning its address
+async fn serve(config: ResizeConfigBuilder) -> String {
+    let config = config.build().unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, get_images_router(config)).await });
+    format!("http://{addr}")
+}
+
+async fn get(url: &str) -> Vec<u8> {
+    let response = reqwest::get(url).await.unwrap();
+    assert!(
+        response.status().is_success(),
+        "{url}: {}",
+        response.status()
+    );
+    response.bytes().await.unwrap().to_vec()
+}
+
+fn write_source(path: &Path) {
+    RgbaImage::from_pixel(32, 32, image::Rgba([200, 100, 50, 255]))
+        .save_with_format(path, ImageFormat::Png)
+        .unwrap();
+}
+
+fn cache_entries(cache_dir: &Path, extension: &str) -> Vec<std::path::PathBuf> {
+    fs::read_dir(cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect()
+}
+
+#[tokio::test]
+async fn survives_restarts_until_the_source_changes() {
+    let dir =
+        std::env::temp_dir().join(format!("image-provider-disk-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (root, cache_dir) = (dir.join("root"), dir.join("cache"));
+    fs::create_dir_all(&root).unwrap();
+    write_source(&root.join("a.png"));
+
+    // Processing writes the entry
+    let url = serve(config(&root, &cache_dir)).await;
+    let processed = get(&format!("{url}/a.png?w=8&output=png")).await;
+    assert!(processed.starts_with(b"\x89PNG"));
+    let entries = cache_entries(&cache_dir, "bin");
+    assert_eq!(entries.len(), 1);
+
+    // A restarted server reads the entry instead of processing again
+    let entry = fs::read(&entries[0]).unwrap();
+    let headers_end = entry.iter().position(|&byte| byte == b'\n').unwrap();
+    let mut marked = entry[..=headers_end].to_vec();
+    marked.extend_from_slice(b"from disk");
+    fs::write(&entries[0], marked).unwrap();
+    let url = serve(config(&root, &cache_dir)).await;
+    assert_eq!(
+        get(&format!("{url}/a.png?w=8&output=png")).await,
+        b"from disk"
+    );
+
+    // Updating the source makes the entry stale
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    write_source(&root.join("a.png"));
+    let url = serve(config(&root, &cache_dir)).await;
+    assert_eq!(get(&format!("{url}/a.png?w=8&output=png")).await, processed);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn precompressed_entries_are_served_to_brotli_clients() {
+    let dir =
+        std::env::temp_dir().join(format!("image-provider-precompress-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (root, cache_dir) = (dir.join("root"), dir.join("cache"));
+    fs::create_dir_all(&root).unwrap();
+    write_source(&root.join("a.png"));
+
+    let url = serve(config(&root, &cache_dir).precompress(true)).await;
+    let url = format!("{url}/a.png?w=16&output=ascii");
+    let identity = get(&url).await;
+    // The compressed copy is written in the background
+    for _ in 0..100 {
+        if !cache_entries(&cache_dir, "br").is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(cache_entries(&cache_dir, "br").len(), 1);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get(&url)
+        .header("accept-encoding", "gzip, br")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.headers()["content-encoding"], "br");
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let vary = response.headers()["vary"].to_str().unwrap().to_string();
+    assert!(vary.contains("accept-encoding"), "{vary}");
+    let compressed = response.bytes().await.unwrap();
+    assert!(compressed.len() < identity.len());
+    let mut decompressed = vec![];
+    brotli::BrotliDecompress(&mut &compressed[..], &mut decompressed).unwrap();
+    assert_eq!(decompressed, identity);
+
+    // Other encodings, refused br and ranges get the image as is
+    for headers in [
+        &[("accept-encoding", "gzip")][..],
+        &[("accept-encoding", "br;q=0")],
+        &[("accept-encoding", "br"), ("range", "bytes=0-9")],
+    ] {
+        let mut request = client.get(&url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = request.send().await.unwrap();
+        assert!(response.status().is_success());
+        assert!(
+            response.headers().get("content-encoding").is_none(),
+            "{headers:?}"
+        );
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{Request, StatusCode},
+    Router,
+};
+use image::ImageFormat;
+use image_provider::{get_images_router, ResizeConfigBuilder};
+use tower::ServiceExt;
+
+/// Router serving `tests/fixtures` with `configure` applied to the default config
+fn router(configure: impl FnOnce(ResizeConfigBuilder) -> ResizeConfigBuilder) -> Router {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = configure(ResizeConfigBuilder::default().root(root));
+    get_images_router(config.build().unwrap())
+}
+
+async fn get(router: &Router, uri: &str, format: ImageFormat) -> Vec<u8> {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK, "{uri}");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(image::guess_format(&body).unwrap(), format);
+    body.to_vec()
+}
+
+#[tokio::test]
+async fn avif_defaults_come_from_the_config() {
+    let uri = "/photo.jpg?output=avif";
+    let default = get(&router(|config| config), uri, ImageFormat::Avif).await;
+    let low = router(|config| config.avif_quality(10).avif_speed(10));
+    let low_quality = get(&low, uri, ImageFormat::Avif).await;
+    assert!(low_quality.len() < default.len());
+
+    // `q` still wins over the configured default
+    let explicit = get(&low, "/photo.jpg?output=avif&q=60", ImageFormat::Avif).await;
+    let explicit_default = get(
+        &router(|config| config.avif_speed(10)),
+        "/photo.jpg?output=avif&q=60",
+        ImageFormat::Avif,
+    )
+    .await;
+    assert_eq!(explicit, explicit_default);
+}
+
+#[tokio::test]
+async fn webp_quality_makes_webp_lossy_by_default() {
+    let uri = "/photo.jpg?output=webp";
+    let lossless = get(&router(|config| config), uri, ImageFormat::WebP).await;
+    let lossy = router(|config| config.webp_quality(Some(50)));
+    let default = get(&lossy, uri, ImageFormat::WebP).await;
+    assert!(default.len() < lossless.len());
+
+    let explicit = get(&lossy, "/photo.jpg?output=webp&q=50", ImageFormat::WebP).await;
+    assert_eq!(default, explicit);
+    let forced = get(
+        &lossy,
+        "/photo.jpg?output=webp&lossless=1",
+        ImageFormat::WebP,
+    )
+    .await;
+    assert_eq!(forced, lossless);
+}
+
+#[tokio::test]
+async fn jpeg_quality_comes_from_the_config() {
+    let uri = "/photo.jpg?w=100";
+    let default = get(&router(|config| config), uri, ImageFormat::Jpeg).await;
+    let low = router(|config| config.jpeg_quality(20));
+    let low_quality = get(&low, uri, ImageFormat::Jpeg).await;
+    assert!(low_quality.len() < default.len());
+
+    let explicit = get(&low, "/photo.jpg?w=100&q=80", ImageFormat::Jpeg).await;
+    assert_eq!(explicit, default);
+}
@@ -0,0 +1,47 @@
+use image_provider::{ImageQuery, ImageQueryError};
+
+#[test]
+fn builds_valid_query() {
+    let query = ImageQuery::builder()
+        .w(300u32)
+        .h(200u32)
+        .dpr("2")
+        .output("webp")
+        .build()
+        .unwrap();
+
+    assert_eq!(query.w, Some(300));
+    assert_eq!(query.dpr.as_deref(), Some("2"));
+    assert_eq!(query.output.as_deref(), Some("webp"));
+    assert_eq!(query.q, None);
+}
+
+#[test]
+fn rejects_invalid_parameters() {
+    let build = |builder: image_provider::ImageQueryBuilder| builder.build().unwrap_err();
+
+    assert_eq!(
+        build(ImageQuery::builder().dpr("0.1")),
+        ImageQueryError::Dpr("0.1".to_string())
+    );
+    assert_eq!(
+        build(ImageQuery::builder().dpr("x")),
+        ImageQueryError::Dpr("x".to_string())
+    );
+    assert_eq!(
+        build(ImageQuery::builder().w(0u32)),
+        ImageQueryError::ZeroSize("w")
+    );
+    assert_eq!(
+        build(ImageQuery::builder().output("docx")),
+        ImageQueryError::Output("docx".to_string())
+    );
+    assert_eq!(
+        build(ImageQuery::builder().q(0u8)),
+        ImageQueryError::Quality(0)
+    );
+    assert_eq!(
+        build(ImageQuery::builder().q(101u8)),
+        ImageQueryError::Quality(101)
+    );
+}
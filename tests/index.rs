@@ -0,0 +1,67 @@
+use std::fs;
+
+use image_provider::{get_images_router, ResizeConfigBuilder};
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn lists_images_in_a_directory() {
+    let root = std::env::temp_dir().join(format!("image-provider-index-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("photos/2024")).unwrap();
+    for file in [
+        "logo.png",
+        "notes.txt",
+        "photos/a.jpg",
+        "photos/b.webp",
+        "photos/2024/c.gif",
+    ] {
+        fs::write(root.join(file), b"").unwrap();
+    }
+
+    let config = ResizeConfigBuilder::default()
+        .root(root.clone())
+        .allow_directory_index(true)
+        .build()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, get_images_router(config)).await });
+
+    let get = |query: &'static str| {
+        let url = format!("{url}/{query}");
+        async move {
+            let response = reqwest::get(url).await.unwrap();
+            let status = response.status().as_u16();
+            let body = response.bytes().await.unwrap();
+            (status, serde_json::from_slice::<Value>(&body).unwrap())
+        }
+    };
+
+    assert_eq!(
+        get("").await,
+        (200, json!([{ "path": "logo.png", "format": "png" }]))
+    );
+    assert_eq!(
+        get("?dir=photos").await,
+        (
+            200,
+            json!([
+                { "path": "photos/a.jpg", "format": "jpeg" },
+                { "path": "photos/b.webp", "format": "webp" },
+            ])
+        )
+    );
+    assert_eq!(
+        get("?dir=photos&recursive=true").await.1,
+        json!([
+            { "path": "photos/2024/c.gif", "format": "gif" },
+            { "path": "photos/a.jpg", "format": "jpeg" },
+            { "path": "photos/b.webp", "format": "webp" },
+        ])
+    );
+    assert_eq!(get("?dir=missing").await.0, 404);
+    assert_eq!(get("?dir=../..").await.0, 404);
+    assert_eq!(get("?dir=logo.png").await.0, 400);
+
+    let _ = fs::remove_dir_all(&root);
+}
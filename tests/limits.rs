@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+use axum::{
+    body::Body,
+    http::{header::RANGE, Request, StatusCode},
+    Router,
+};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+use tower::ServiceExt;
+
+fn router(config: ResizeConfigBuilder) -> Router {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    get_images_router(config.root(root).build().unwrap())
+}
+
+async fn status(router: &Router, uri: &str, range: Option<&str>) -> StatusCode {
+    let mut request = Request::get(uri);
+    if let Some(range) = range {
+        request = request.header(RANGE, range);
+    }
+    let request = request.body(Body::empty()).unwrap();
+    router.clone().oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn large_sources_are_rejected() {
+    // quadrants.png is 862 bytes
+    let limited = router(ResizeConfigBuilder::default().max_source_file_bytes(Some(100)));
+    assert_eq!(
+        status(&limited, "/quadrants.png", None).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    assert_eq!(
+        status(&limited, "/quadrants.png?w=100", None).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    let allowed = router(ResizeConfigBuilder::default().max_source_file_bytes(Some(1024)));
+    assert_eq!(
+        status(&allowed, "/quadrants.png", None).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn large_ranges_are_rejected() {
+    let router = router(ResizeConfigBuilder::default().max_range_size(Some(100)));
+    for uri in ["/quadrants.png", "/quadrants.png?w=100"] {
+        assert_eq!(
+            status(&router, uri, Some("bytes=0-99")).await,
+            StatusCode::PARTIAL_CONTENT
+        );
+        assert_eq!(
+            status(&router, uri, Some("bytes=0-100")).await,
+            StatusCode::RANGE_NOT_SATISFIABLE
+        );
+        assert_eq!(
+            status(&router, uri, Some("bytes=100-")).await,
+            StatusCode::RANGE_NOT_SATISFIABLE
+        );
+        assert_eq!(status(&router, uri, None).await, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn output_size_is_checked_after_dpr() {
+    let router = router(
+        ResizeConfigBuilder::default()
+            .max_width(500)
+            .max_height(400),
+    );
+    let ok = StatusCode::OK;
+    assert_eq!(status(&router, "/quadrants.png?w=400", None).await, ok);
+    assert_eq!(
+        status(&router, "/quadrants.png?w=250&dpr=2", None).await,
+        ok
+    );
+    let too_large = StatusCode::BAD_REQUEST;
+    assert_eq!(
+        status(&router, "/quadrants.png?w=400&dpr=2", None).await,
+        too_large
+    );
+    assert_eq!(
+        status(&router, "/quadrants.png?w=600", None).await,
+        too_large
+    );
+    assert_eq!(
+        status(&router, "/quadrants.png?h=180&dpr=2", None).await,
+        ok
+    );
+    assert_eq!(
+        status(&router, "/quadrants.png?h=250&dpr=2", None).await,
+        too_large
+    );
+}
@@ -0,0 +1,48 @@
+use std::{fs, path::Path};
+
+use image::{ImageFormat, RgbaImage};
+use image_provider::{get_images_router_multi, ResizeConfigBuilder};
+
+fn write_source(path: &Path) {
+    RgbaImage::from_pixel(16, 16, image::Rgba([20, 40, 60, 255]))
+        .save_with_format(path, ImageFormat::Png)
+        .unwrap();
+}
+
+async fn status(url: &str) -> u16 {
+    reqwest::get(url).await.unwrap().status().as_u16()
+}
+
+#[tokio::test]
+async fn serves_each_mount_from_its_own_root() {
+    let dir = std::env::temp_dir().join(format!("image-provider-multi-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (uploads, assets) = (dir.join("uploads"), dir.join("assets"));
+    fs::create_dir_all(&uploads).unwrap();
+    fs::create_dir_all(&assets).unwrap();
+    write_source(&uploads.join("a.png"));
+    write_source(&assets.join("b.png"));
+
+    let router = get_images_router_multi(
+        vec![
+            ("uploads".to_string(), uploads),
+            ("assets".to_string(), assets),
+        ],
+        ResizeConfigBuilder::default().build().unwrap(),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    assert_eq!(status(&format!("{url}/uploads/a.png?w=8")).await, 200);
+    assert_eq!(status(&format!("{url}/assets/b.png?w=8")).await, 200);
+    // Each mount only sees its own directory
+    assert_eq!(status(&format!("{url}/assets/a.png")).await, 404);
+    assert_eq!(
+        status(&format!("{url}/assets/..%2fuploads%2fa.png")).await,
+        404
+    );
+    assert_eq!(status(&format!("{url}/missing/a.png")).await, 404);
+
+    let _ = fs::remove_dir_all(&dir);
+}
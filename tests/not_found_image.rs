@@ -0,0 +1,91 @@
+use std::{fs, path::PathBuf};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+    Router,
+};
+use image::{GenericImageView, ImageFormat};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+use tower::ServiceExt;
+
+fn router(not_found_image: &str) -> Router {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = ResizeConfigBuilder::default()
+        .not_found_image(Some(root.join(not_found_image)))
+        .root(root)
+        .build()
+        .unwrap();
+    get_images_router(config)
+}
+
+async fn get(router: &Router, uri: &str) -> (StatusCode, String, Vec<u8>) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let content_type = response.headers()[CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, content_type, body.to_vec())
+}
+
+#[tokio::test]
+async fn missing_images_get_the_placeholder() {
+    let router = router("quadrants.png");
+
+    let (status, content_type, body) = get(&router, "/missing.png?w=100&output=webp").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(content_type, "image/webp");
+    let image = image::load_from_memory_with_format(&body, ImageFormat::WebP).unwrap();
+    assert_eq!(image.dimensions(), (100, 75));
+
+    let (status, content_type, _) = get(&router, "/missing/deeper.jpg").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(content_type, "image/png");
+
+    // Existing images are unaffected
+    let (status, ..) = get(&router, "/photo.jpg?w=100").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn missing_secondary_inputs_are_a_plain_404() {
+    // The placeholder directory has an overlay.png, the root does not
+    let placeholders =
+        std::env::temp_dir().join(format!("image-provider-not-found-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&placeholders);
+    fs::create_dir_all(&placeholders).unwrap();
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    for name in ["placeholder.png", "overlay.png"] {
+        fs::copy(fixtures.join("quadrants.png"), placeholders.join(name)).unwrap();
+    }
+    let config = ResizeConfigBuilder::default()
+        .not_found_image(Some(placeholders.join("placeholder.png")))
+        .root(fixtures)
+        .build()
+        .unwrap();
+    let router = get_images_router(config);
+
+    for uri in [
+        "/photo.jpg?watermark=overlay.png",
+        "/photo.jpg?diff=overlay.png",
+        "/photo.jpg?stitch=overlay.png",
+    ] {
+        let (status, content_type, body) = get(&router, uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        assert_eq!(content_type, "application/json", "{uri}");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "NOT_FOUND");
+    }
+}
+
+#[tokio::test]
+async fn missing_placeholder_is_a_plain_404() {
+    let (status, content_type, body) = get(&router("placeholder.png"), "/missing.png").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(content_type, "application/json");
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "NOT_FOUND");
+}
@@ -0,0 +1,701 @@
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use image::{GenericImageView, ImageFormat};
+use image_provider::{
+    get_images_router, get_images_router_with_events, sanitize_path, ImageEventHandler,
+    ResizeConfigBuilder,
+};
+use tokio_util::io::ReaderStream;
+use tower::ServiceExt;
+
+/// Router serving `tests/fixtures`: `quadrants.png` (320x240), `photo.jpg` (160x120) and
+/// `icon.svg`
+fn router() -> Router {
+    router_with(|config| config)
+}
+
+fn fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// [`router`] with `configure` applied to its config
+fn router_with(configure: impl FnOnce(ResizeConfigBuilder) -> ResizeConfigBuilder) -> Router {
+    let root = fixtures();
+    let config = configure(ResizeConfigBuilder::default().root(root));
+    get_images_router(config.build().unwrap())
+}
+
+/// `GET uri` with extra request headers
+async fn get_with(router: &Router, uri: &str, headers: &[(&str, &str)]) -> Response {
+    let mut request = Request::get(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let request = request.body(Body::empty()).unwrap();
+    router.clone().oneshot(request).await.unwrap()
+}
+
+async fn get(router: &Router, uri: &str) -> (StatusCode, Option<String>, Vec<u8>) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, content_type, body.to_vec())
+}
+
+/// `POST uri` with a `multipart/form-data` body of a text field and `file` as `upload.png`,
+/// streamed in 16 KiB chunks
+async fn upload(router: &Router, uri: &str, file: &[u8]) -> (StatusCode, Option<String>, Vec<u8>) {
+    const BOUNDARY: &str = "image-provider-boundary";
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nphoto\r\n\
+         --{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"upload.png\"\r\n\
+         Content-Type: image/png\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+
+    let request = Request::post(uri)
+        .header(
+            CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        )
+        .body(Body::from_stream(ReaderStream::with_capacity(
+            Cursor::new(body),
+            16 * 1024,
+        )))
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, content_type, body.to_vec())
+}
+
+fn decode(bytes: &[u8], format: ImageFormat) -> (u32, u32) {
+    assert_eq!(image::guess_format(bytes).unwrap(), format);
+    image::load_from_memory(bytes).unwrap().dimensions()
+}
+
+#[tokio::test]
+async fn serves_original() {
+    let (status, content_type, body) = get(&router(), "/quadrants.png").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("image/png"));
+    let original = std::fs::read(fixtures().join("quadrants.png")).unwrap();
+    assert_eq!(body, original);
+}
+
+#[tokio::test]
+async fn resizes_by_width() {
+    let (status, _, body) = get(&router(), "/quadrants.png?w=100").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(decode(&body, ImageFormat::Png), (100, 75));
+
+    let (status, _, body) = get(&router(), "/photo.jpg?w=100").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(decode(&body, ImageFormat::Jpeg), (100, 75));
+}
+
+#[tokio::test]
+async fn converts_format() {
+    let (status, content_type, body) = get(&router(), "/photo.jpg?output=webp").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("image/webp"));
+    assert_eq!(decode(&body, ImageFormat::WebP), (160, 120));
+}
+
+#[tokio::test]
+async fn missing_file_is_not_found() {
+    let (status, ..) = get(&router(), "/missing.png").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn unsupported_output_is_bad_request() {
+    let (status, _, body) = get(&router(), "/quadrants.png?output=docx").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "UNSUPPORTED_FORMAT");
+}
+
+#[tokio::test]
+async fn repeated_requests_are_identical() {
+    let router = router();
+    let (_, _, first) = get(&router, "/photo.jpg?w=64&output=png").await;
+    let (_, _, second) = get(&router, "/photo.jpg?w=64&output=png").await;
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn dpr_scales_output() {
+    let (status, _, body) = get(&router(), "/quadrants.png?dpr=2&w=100").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(decode(&body, ImageFormat::Png), (200, 150));
+
+    // Above max_dpr (3 by default) is lowered to it rather than rejected
+    let (status, _, body) = get(&router(), "/quadrants.png?dpr=10&w=100").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(decode(&body, ImageFormat::Png), (300, 225));
+}
+
+#[tokio::test]
+async fn svg_is_rasterized() {
+    let router = router();
+    if !cfg!(feature = "svg") {
+        let (status, _, body) = get(&router, "/icon.svg?w=32").await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "UNSUPPORTED_MEDIA_TYPE");
+        return;
+    }
+
+    // The viewBox is 10x10, a red square
+    for (uri, size) in [
+        ("/icon.svg", (10, 10)),
+        ("/icon.svg?w=32", (32, 32)),
+        ("/icon.svg?w=16&h=8", (16, 8)),
+        ("/icon.svg?w=20&dpr=2", (40, 40)),
+    ] {
+        let (status, content_type, body) = get(&router, uri).await;
+        assert_eq!(status, StatusCode::OK, "{uri}");
+        assert_eq!(content_type.as_deref(), Some("image/png"), "{uri}");
+        assert_eq!(decode(&body, ImageFormat::Png), size, "{uri}");
+        let image = image::load_from_memory(&body).unwrap().to_rgba8();
+        assert_eq!(image.get_pixel(size.0 / 2, size.1 / 2).0, [255, 0, 0, 255], "{uri}");
+    }
+
+    let root = std::env::temp_dir().join(format!("image-provider-svg-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("empty.svg"),
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="0" height="0"/>"#,
+    )
+    .unwrap();
+    let config = ResizeConfigBuilder::default().root(root.clone());
+    let router = get_images_router(config.build().unwrap());
+    let (status, _, _) = get(&router, "/empty.svg?w=32").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test]
+async fn quality_is_an_alias_of_q() {
+    let router = router();
+    for output in ["jpeg", "webp"] {
+        let (status, _, low) = get(
+            &router,
+            &format!("/photo.jpg?w=100&output={output}&quality=10"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, _, q) = get(&router, &format!("/photo.jpg?w=100&output={output}&q=10")).await;
+        assert_eq!(low, q);
+        let (_, _, high) = get(
+            &router,
+            &format!("/photo.jpg?w=100&output={output}&quality=95"),
+        )
+        .await;
+        assert!(low.len() < high.len());
+    }
+
+    for invalid in ["q=0", "quality=0", "q=101", "quality=101"] {
+        let (status, ..) = get(&router, &format!("/photo.jpg?output=jpeg&{invalid}")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{invalid}");
+    }
+}
+
+#[tokio::test]
+async fn ascii_of_an_extreme_aspect_ratio() {
+    // 1x100, so h=1 rounds the width to 0
+    let (status, _, body) = get(&router(), "/quadrants.png?crop=0,0,1,100&h=1&output=ascii").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(String::from_utf8(body).unwrap().lines().count(), 1);
+}
+
+#[tokio::test]
+async fn ascii_of_a_pyramid_level() {
+    let (status, content_type, body) =
+        get(&router(), "/quadrants.png?output=ascii&gaussian_pyramid=2").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(content_type.unwrap().starts_with("text/plain"));
+    // 320x240 halved twice, one character per pixel
+    let text = String::from_utf8(body).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 60);
+    assert!(lines.iter().all(|line| line.chars().count() == 80));
+}
+
+#[tokio::test]
+async fn cors_is_off_unless_configured() {
+    let origin = [("origin", "https://a.example")];
+    let response = get_with(&router(), "/photo.jpg", &origin).await;
+    assert!(!response
+        .headers()
+        .contains_key("access-control-allow-origin"));
+
+    let listed = router_with(|config| config.cors_origins(Some(vec!["https://a.example".into()])));
+    let response = get_with(&listed, "/photo.jpg", &origin).await;
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://a.example"
+    );
+    let other = [("origin", "https://b.example")];
+    let response = get_with(&listed, "/photo.jpg", &other).await;
+    assert!(!response
+        .headers()
+        .contains_key("access-control-allow-origin"));
+
+    let any = router_with(|config| config.cors_origins(Some(vec!["*".into()])));
+    let response = get_with(&any, "/photo.jpg", &origin).await;
+    assert_eq!(response.headers()["access-control-allow-origin"], "*");
+}
+
+/// Whether `webp` is encoded lossy (`VP8 `) rather than lossless (`VP8L`)
+fn is_lossy_webp(webp: &[u8]) -> bool {
+    webp.windows(4).take(64).any(|chunk| chunk == b"VP8 ")
+}
+
+#[tokio::test]
+async fn thumb_applies_the_preset() {
+    let (status, content_type, body) = get(&router(), "/photo.jpg?thumb=1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("image/webp"));
+    // Covered rather than letterboxed, and encoded lossy
+    assert_eq!(decode(&body, ImageFormat::WebP), (300, 300));
+    assert!(is_lossy_webp(&body));
+
+    let (_, _, body) = get(&router(), "/photo.jpg?thumb=1&w=100&lossless=1").await;
+    assert_eq!(decode(&body, ImageFormat::WebP), (100, 300));
+    assert!(!is_lossy_webp(&body));
+}
+
+#[tokio::test]
+async fn negotiates_output_from_accept() {
+    let router = router();
+    let avif = [("accept", "image/avif,image/webp,*/*;q=0.8")];
+    let response = get_with(&router, "/photo.jpg?w=32", &avif).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/avif");
+    assert_eq!(response.headers()["vary"], "accept");
+
+    // JPEG sources become lossy WebP rather than a far larger lossless one
+    let webp = [("accept", "image/webp,*/*;q=0.8")];
+    let response = get_with(&router, "/photo.jpg?w=100", &webp).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/webp");
+    assert_eq!(response.headers()["vary"], "accept");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(is_lossy_webp(&body));
+
+    // Same URL, so only a cache key with the negotiated format keeps them apart
+    let response = get_with(&router, "/photo.jpg?w=100", &[("accept", "image/jpeg")]).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/jpeg");
+    let response = get_with(&router, "/photo.jpg?w=100", &[]).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/jpeg");
+    let response = get_with(&router, "/photo.jpg?w=100", &webp).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/webp");
+}
+
+#[tokio::test]
+async fn negotiation_keeps_originals_and_lossless_sources() {
+    let router = router();
+    let avif = [("accept", "image/avif,*/*")];
+
+    // Nothing to process, so the original is served as is
+    let original = std::fs::read(fixtures().join("quadrants.png")).unwrap();
+    let response = get_with(&router, "/quadrants.png", &avif).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+    assert!(!response.headers().contains_key("vary"));
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, original);
+
+    // A resized PNG stays lossless
+    let response = get_with(&router, "/quadrants.png?w=100", &avif).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+    let webp = [("accept", "image/avif,image/webp,*/*")];
+    let response = get_with(&router, "/quadrants.png?w=100", &webp).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/webp");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(!is_lossy_webp(&body));
+
+    // Unless a quality is asked for
+    let response = get_with(&router, "/quadrants.png?w=100&q=80", &avif).await;
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/avif");
+}
+
+#[tokio::test]
+async fn etag_answers_if_none_match_with_304() {
+    let router = router();
+    for uri in ["/photo.jpg", "/photo.jpg?w=100"] {
+        let response = get_with(&router, uri, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+        let response = get_with(&router, uri, &[("if-none-match", &etag)]).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{uri}");
+        assert_eq!(response.headers()["etag"], etag.as_str());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        let response = get_with(&router, uri, &[("if-none-match", "\"other\"")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Different parameters are a different representation
+    let full = get_with(&router, "/photo.jpg?w=100", &[]).await;
+    let half = get_with(&router, "/photo.jpg?w=50", &[]).await;
+    assert_ne!(full.headers()["etag"], half.headers()["etag"]);
+}
+
+#[tokio::test]
+async fn info_is_cached_and_revalidated() {
+    let router = router_with(|config| config.info_max_age(60));
+    let response = get_with(&router, "/info/quadrants.png", &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["cache-control"], "public, max-age=60");
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        (info["width"].as_u64(), info["height"].as_u64()),
+        (Some(320), Some(240))
+    );
+
+    let response = get_with(&router, "/info/quadrants.png", &[("if-none-match", &etag)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["cache-control"], "public, max-age=60");
+
+    let other = get_with(&router, "/info/photo.jpg", &[]).await;
+    assert_ne!(other.headers()["etag"], etag.as_str());
+}
+
+#[tokio::test]
+async fn fit_controls_the_output_size() {
+    // quadrants.png is 320x240 (4:3)
+    let cases = [
+        ("cover", (100, 100)),
+        ("fill", (100, 100)),
+        ("contain", (100, 75)),
+    ];
+    for (fit, size) in cases {
+        let uri = format!("/quadrants.png?w=100&h=100&fit={fit}");
+        let (status, _, body) = get(&router(), &uri).await;
+        assert_eq!(status, StatusCode::OK, "{fit}");
+        assert_eq!(decode(&body, ImageFormat::Png), size, "{fit}");
+    }
+
+    let (status, ..) = get(&router(), "/quadrants.png?w=100&h=100&fit=stretch").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[derive(Default)]
+struct EncodeCounter(AtomicUsize);
+
+impl ImageEventHandler for EncodeCounter {
+    fn on_encode_complete(&self, _path: &Path, _bytes: usize, _elapsed_ms: u64) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_identical_requests_are_processed_once() {
+    let root = fixtures();
+    let config = ResizeConfigBuilder::default().root(root).build().unwrap();
+    let counter = Arc::new(EncodeCounter::default());
+    let router = get_images_router_with_events(config, counter.clone());
+
+    // Large enough that the requests overlap while the first one is processed
+    let uri = "/quadrants.png?w=2400&output=png&compress=best";
+    let requests: Vec<_> = (0..8)
+        .map(|_| {
+            let router = router.clone();
+            tokio::spawn(async move { get(&router, uri).await })
+        })
+        .collect();
+    let mut bodies = vec![];
+    for request in requests {
+        let (status, _, body) = request.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        bodies.push(body);
+    }
+    assert!(bodies.windows(2).all(|pair| pair[0] == pair[1]));
+    assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn enhance_needs_the_feature_and_a_service() {
+    let (status, _, body) = get(&router(), "/photo.jpg?enhance=auto").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let expected = match cfg!(feature = "enhance") {
+        true => "enhance_service_url",
+        false => "--features enhance",
+    };
+    assert!(body["message"].as_str().unwrap().contains(expected));
+}
+
+#[tokio::test]
+async fn errors_are_json() {
+    let cases = [
+        ("/missing.png", StatusCode::NOT_FOUND, "NOT_FOUND"),
+        (
+            "/photo.jpg?output=bmp2",
+            StatusCode::BAD_REQUEST,
+            "UNSUPPORTED_FORMAT",
+        ),
+        ("/photo.jpg?w=0", StatusCode::BAD_REQUEST, "BAD_REQUEST"),
+    ];
+    for (uri, expected, code) in cases {
+        let (status, content_type, body) = get(&router(), uri).await;
+        assert_eq!(status, expected, "{uri}");
+        assert_eq!(content_type.as_deref(), Some("application/json"), "{uri}");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], code, "{uri}");
+        assert!(!body["message"].as_str().unwrap().is_empty(), "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn traversal_and_null_bytes_are_rejected() {
+    let router = router();
+    // `%2E%2E` decodes to `..` before the handler sees it
+    let (status, ..) = get(&router, "/%2E%2E/Cargo.toml").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    // Decoded once only, `%2E%2E` is then a plain name
+    let (status, ..) = get(&router, "/%252E%252E/Cargo.toml").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, ..) = get(&router, "/photo.jpg%00.png").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let root = fixtures();
+    assert!(sanitize_path(&root, "../Cargo.toml").is_err());
+    assert!(sanitize_path(&root, "a/b/../../../Cargo.toml").is_err());
+    assert!(sanitize_path(&root, "a/../photo.jpg").is_err());
+    assert!(sanitize_path(&root, "photo.jpg\0").is_err());
+    assert!(sanitize_path(&root, "/etc/passwd").is_err());
+    assert_eq!(
+        sanitize_path(&root, "./photo.jpg")
+            .unwrap_err()
+            .status_code(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        sanitize_path(&root, "photo.jpg").unwrap(),
+        root.canonicalize().unwrap().join("photo.jpg")
+    );
+}
+
+#[tokio::test]
+async fn percent_signs_are_part_of_file_names() {
+    let root = std::env::temp_dir().join(format!("image-provider-percent-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    let original = std::fs::read(fixtures().join("quadrants.png")).unwrap();
+    std::fs::write(root.join("100%.png"), &original).unwrap();
+    std::fs::write(root.join("100%25.png"), b"not this one").unwrap();
+
+    let config = ResizeConfigBuilder::default().root(root.clone());
+    let router = get_images_router(config.build().unwrap());
+    let (status, _, body) = get(&router, "/100%25.png").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, original);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn flip_and_rotate_move_pixels() {
+    let original = image::open(fixtures().join("quadrants.png")).unwrap();
+    let (width, height) = original.dimensions();
+    // Centers of the four quadrants
+    let points = [(80, 60), (240, 60), (80, 180), (240, 180)];
+    let colors: Vec<_> = points
+        .iter()
+        .map(|&(x, y)| original.get_pixel(x, y))
+        .collect();
+    assert!((1..4).all(|i| !colors[..i].contains(&colors[i])));
+
+    type Map = fn(u32, u32, u32, u32) -> (u32, u32);
+    let cases: [(&str, (u32, u32), Map); 5] = [
+        ("flip=h", (320, 240), |x, y, w, _| (w - 1 - x, y)),
+        ("flip=v", (320, 240), |x, y, _, h| (x, h - 1 - y)),
+        ("rotate=90", (240, 320), |x, y, _, h| (h - 1 - y, x)),
+        ("rotate=180", (320, 240), |x, y, w, h| {
+            (w - 1 - x, h - 1 - y)
+        }),
+        ("rotate=270", (240, 320), |x, y, w, _| (y, w - 1 - x)),
+    ];
+    for (query, size, map) in cases {
+        let (status, _, body) = get(&router(), &format!("/quadrants.png?{query}")).await;
+        assert_eq!(status, StatusCode::OK, "{query}");
+        let output = image::load_from_memory(&body).unwrap();
+        assert_eq!(output.dimensions(), size, "{query}");
+        for (x, y) in points {
+            let (to_x, to_y) = map(x, y, width, height);
+            assert_eq!(
+                output.get_pixel(to_x, to_y),
+                original.get_pixel(x, y),
+                "{query} ({x}, {y})"
+            );
+        }
+    }
+
+    // Rotating first means `w` applies to the turned image
+    let (_, _, body) = get(&router(), "/quadrants.png?rotate=90&w=120").await;
+    assert_eq!(decode(&body, ImageFormat::Png), (120, 160));
+}
+
+#[tokio::test]
+async fn avif_codec_is_checked() {
+    let router = router();
+    let (status, content_type, _) =
+        get(&router, "/quadrants.png?w=32&output=avif&avif_codec=rav1e").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("image/avif"));
+
+    for codec in ["libaom", "svt"] {
+        let uri = format!("/quadrants.png?w=32&output=avif&avif_codec={codec}");
+        let (status, _, body) = get(&router, &uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{codec}");
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let message = body["message"].as_str().unwrap();
+        assert!(message.contains("available: rav1e"), "{message}");
+    }
+}
+
+#[tokio::test]
+async fn uploads_are_detected_and_converted() {
+    let converting = router_with(|config| {
+        config
+            .enable_upload(true)
+            .upload_allowed_extensions(vec!["jpeg".into(), "png".into()])
+            .upload_output(Some("webp".into()))
+    });
+    // Named upload.png, detected from the content
+    let photo = std::fs::read(fixtures().join("photo.jpg")).unwrap();
+
+    let (status, content_type, body) = upload(&converting, "/upload", &photo).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("image/webp"));
+    assert_eq!(decode(&body, ImageFormat::WebP), (160, 120));
+
+    let (status, _, info) = upload(&converting, "/upload?response=json", &photo).await;
+    assert_eq!(status, StatusCode::OK);
+    let info: serde_json::Value = serde_json::from_slice(&info).unwrap();
+    assert_eq!(
+        info,
+        serde_json::json!({
+            "detected_format": "jpeg",
+            "output_format": "webp",
+            "width": 160,
+            "height": 120,
+            "bytes": body.len(),
+        })
+    );
+
+    let mut gif = std::io::Cursor::new(vec![]);
+    image::RgbaImage::new(4, 4)
+        .write_to(&mut gif, ImageFormat::Gif)
+        .unwrap();
+    let (status, ..) = upload(&converting, "/upload", gif.get_ref()).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let (status, ..) = upload(&converting, "/upload", b"not an image").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let limited = router_with(|config| config.enable_upload(true).max_source_file_bytes(Some(100)));
+    let (status, ..) = upload(&limited, "/upload", &photo).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Off unless enabled
+    let (status, ..) = upload(&router(), "/upload", &photo).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn large_uploads_are_streamed() {
+    // Noise barely compresses, so this is well above axum's 2 MB default body limit
+    let mut seed = 1u32;
+    let noise = image::RgbImage::from_fn(1000, 1000, |_, _| {
+        seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let [r, g, b, _] = seed.to_le_bytes();
+        image::Rgb([r, g, b])
+    });
+    let mut png = Cursor::new(vec![]);
+    noise.write_to(&mut png, ImageFormat::Png).unwrap();
+    let png = png.into_inner();
+    assert!(png.len() > 2 * 1024 * 1024);
+
+    let uploads = router_with(|config| config.enable_upload(true));
+    let (status, _, body) = upload(&uploads, "/upload?response=json", &png).await;
+    assert_eq!(status, StatusCode::OK);
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        (info["width"].as_u64(), info["height"].as_u64()),
+        (Some(1000), Some(1000))
+    );
+
+    // The limit applies while the chunks arrive
+    let limited = router_with(|config| {
+        config
+            .enable_upload(true)
+            .max_source_file_bytes(Some(1024 * 1024))
+    });
+    let (status, _, body) = upload(&limited, "/upload", &png).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error["code"], "SOURCE_TOO_LARGE");
+}
+
+#[tokio::test]
+async fn metrics_are_served_in_the_prometheus_format() {
+    let admin = router_with(|config| config.enable_admin_endpoints(true));
+    for uri in ["/photo.jpg?w=50", "/photo.jpg?w=50", "/missing.jpg?w=50"] {
+        get(&admin, uri).await;
+    }
+
+    let (status, content_type, body) = get(&admin, "/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(content_type
+        .unwrap()
+        .starts_with("text/plain; version=0.0.4"));
+    let body = String::from_utf8(body).unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    for line in [
+        "# TYPE image_provider_requests_total counter",
+        "image_provider_requests_total{status=\"200\"} 2",
+        "image_provider_requests_total{status=\"404\"} 1",
+        "image_provider_cache_hits_total 1",
+        "image_provider_cache_misses_total 1",
+        "# TYPE image_provider_processing_duration_seconds histogram",
+        "image_provider_processing_duration_seconds_count 1",
+    ] {
+        assert!(lines.contains(&line), "{line} in {body}");
+    }
+
+    // Admin endpoints are off by default
+    let (status, ..) = get(&router(), "/metrics").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use image_provider::{get_images_router, sign, sign_url, ResizeConfigBuilder};
+use tower::ServiceExt;
+
+const SECRET: &str = "s3cret";
+
+fn router() -> Router {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = ResizeConfigBuilder::default()
+        .root(root)
+        .secret(Some(SECRET.to_string()))
+        .build()
+        .unwrap();
+    get_images_router(config)
+}
+
+async fn status(uri: &str) -> StatusCode {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    router().oneshot(request).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn only_signed_requests_are_served() {
+    let sig = sign(SECRET, "/photo.jpg", "w=100&output=webp");
+    let signed = format!("/photo.jpg?w=100&output=webp&s={sig}");
+    assert_eq!(status(&signed).await, StatusCode::OK);
+    // sig is an alias of s
+    let aliased = format!("/photo.jpg?w=100&output=webp&sig={sig}");
+    assert_eq!(status(&aliased).await, StatusCode::OK);
+
+    // Parameter order does not matter
+    let reordered = format!("/photo.jpg?s={sig}&output=webp&w=100");
+    assert_eq!(status(&reordered).await, StatusCode::OK);
+
+    assert_eq!(
+        status("/photo.jpg?w=100&output=webp").await,
+        StatusCode::FORBIDDEN
+    );
+    let tampered = format!("/photo.jpg?w=2000&output=webp&s={sig}");
+    assert_eq!(status(&tampered).await, StatusCode::FORBIDDEN);
+    let other_path = format!("/quadrants.png?w=100&output=webp&s={sig}");
+    assert_eq!(status(&other_path).await, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn sign_url_replaces_an_existing_sig() {
+    let signed = sign_url(SECRET, "/photo.jpg?w=100&sig=stale&s=stale&output=webp");
+    assert_eq!(signed.matches("s=").count(), 1);
+    assert!(!signed.contains("sig=") && !signed.contains("stale"));
+    assert_eq!(status(&signed).await, StatusCode::OK);
+    assert_eq!(signed, sign_url(SECRET, &signed));
+
+    let bare = sign_url(SECRET, "/photo.jpg");
+    assert!(bare.starts_with("/photo.jpg?s="));
+    assert_eq!(status(&bare).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn signed_operations_refuses_unverified_parameters() {
+    let signed = sign_url(SECRET, "/photo.jpg?w=100&signed_operations=1");
+    assert_eq!(status(&signed).await, StatusCode::OK);
+
+    // Injected operations break the signature
+    let injected = format!("{signed}&blur=5");
+    assert_eq!(status(&injected).await, StatusCode::FORBIDDEN);
+    // A second sig is not covered by the signature either
+    for extra in ["s=0", "sig=0", "sig"] {
+        let resigned = format!("{signed}&{extra}");
+        assert_eq!(status(&resigned).await, StatusCode::FORBIDDEN, "{extra}");
+    }
+    let lenient = sign_url(SECRET, "/photo.jpg?w=100");
+    assert_eq!(status(&format!("{lenient}&sig=0")).await, StatusCode::OK);
+
+    // Nothing can be verified without a secret
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let config = ResizeConfigBuilder::default().root(root).build().unwrap();
+    let unsigned = get_images_router(config);
+    let status = |uri: &'static str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = unsigned.clone().oneshot(request);
+        async { response.await.unwrap().status() }
+    };
+    assert_eq!(
+        status("/photo.jpg?w=100&signed_operations=1").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(status("/photo.jpg?w=100").await, StatusCode::OK);
+}
@@ -0,0 +1,63 @@
+use std::{fs, path::Path};
+
+use image::{ImageFormat, RgbImage};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+
+async fn serve(root: &Path) -> String {
+    let config = ResizeConfigBuilder::default()
+        .root(root.to_path_buf())
+        .build()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, get_images_router(config)).await });
+    format!("http://{addr}")
+}
+
+/// Fetch `url` and decode the body, checking it is `format`
+async fn get_image(url: &str, format: ImageFormat) -> image::DynamicImage {
+    let response = reqwest::get(url).await.unwrap();
+    assert!(
+        response.status().is_success(),
+        "{url}: {}",
+        response.status()
+    );
+    let content_type = response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(content_type, format.to_mime_type());
+    let bytes = response.bytes().await.unwrap();
+    assert_eq!(image::guess_format(&bytes).unwrap(), format);
+    image::load_from_memory_with_format(&bytes, format).unwrap()
+}
+
+#[tokio::test]
+async fn round_trips_tiff() {
+    let root = std::env::temp_dir().join(format!("image-provider-tiff-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    RgbImage::from_pixel(32, 16, image::Rgb([10, 120, 240]))
+        .save_with_format(root.join("a.tiff"), ImageFormat::Tiff)
+        .unwrap();
+    RgbImage::from_pixel(32, 16, image::Rgb([240, 120, 10]))
+        .save_with_format(root.join("b.png"), ImageFormat::Png)
+        .unwrap();
+    let url = serve(&root).await;
+
+    // TIFF in, TIFF out
+    let resized = get_image(&format!("{url}/a.tiff?w=8&h=4"), ImageFormat::Tiff).await;
+    assert_eq!((resized.width(), resized.height()), (8, 4));
+    assert_eq!(resized.to_rgb8().get_pixel(4, 2).0, [10, 120, 240]);
+
+    // TIFF in, another format out
+    let png = get_image(&format!("{url}/a.tiff?w=8&output=png"), ImageFormat::Png).await;
+    assert_eq!(png.width(), 8);
+
+    // Another format in, TIFF out
+    let tiff = get_image(&format!("{url}/b.png?w=16&output=tiff"), ImageFormat::Tiff).await;
+    assert_eq!((tiff.width(), tiff.height()), (16, 8));
+    assert_eq!(tiff.to_rgb8().get_pixel(8, 4).0, [240, 120, 10]);
+
+    let _ = fs::remove_dir_all(&root);
+}
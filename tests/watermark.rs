@@ -0,0 +1,60 @@
+use std::fs;
+
+use image::{ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use image_provider::{get_images_router, ResizeConfigBuilder};
+
+#[tokio::test]
+async fn overlays_bottom_right_corner() {
+    let root =
+        std::env::temp_dir().join(format!("image-provider-watermark-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    RgbImage::from_pixel(400, 200, Rgb([0, 0, 255]))
+        .save_with_format(root.join("photo.png"), ImageFormat::Png)
+        .unwrap();
+    RgbaImage::from_pixel(40, 20, Rgba([255, 0, 0, 255]))
+        .save_with_format(root.join("logo.png"), ImageFormat::Png)
+        .unwrap();
+    RgbImage::from_pixel(40, 20, Rgb([255, 0, 0]))
+        .save_with_format(root.join("logo.jpg"), ImageFormat::Jpeg)
+        .unwrap();
+
+    let config = ResizeConfigBuilder::default()
+        .root(root.clone())
+        .watermark_scale(0.2)
+        .watermark_opacity(1.0)
+        .build()
+        .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, get_images_router(config)).await });
+
+    let response = reqwest::get(format!("{url}/photo.png?w=200&watermark=logo.png"))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let image = image::load_from_memory(&response.bytes().await.unwrap())
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(image.dimensions(), (200, 100));
+
+    // 20% of the 100px shorter side is a 20x10 logo, 2px from the corner
+    let red: Vec<(u32, u32)> = image
+        .enumerate_pixels()
+        .filter(|(.., pixel)| pixel.0[0] > 128)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+    assert_eq!(red.first(), Some(&(178, 88)));
+    assert_eq!(red.last(), Some(&(197, 97)));
+    assert_eq!(red.len(), 20 * 10);
+
+    let status = |query: &'static str| {
+        let url = format!("{url}/photo.png?w=200&{query}");
+        async move { reqwest::get(url).await.unwrap().status().as_u16() }
+    };
+    assert_eq!(status("watermark=../../etc/passwd").await, 404);
+    assert_eq!(status("watermark=missing.png").await, 404);
+    assert_eq!(status("watermark=logo.jpg").await, 400);
+
+    let _ = fs::remove_dir_all(&root);
+}